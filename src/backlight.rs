@@ -0,0 +1,177 @@
+use crate::sysfs::{list_paths, read_value};
+use crossterm::style::Stylize;
+
+/*
+    Display backlight dimming on battery, manual changes pause it until the
+    next power source transition
+*/
+
+const BACKLIGHT_PATH: &str = "/sys/class/backlight";
+
+#[derive(Debug, Clone, Default)]
+pub struct BacklightState {
+    // brightness before dimming, restored on the next transition
+    saved: Vec<(String, u32)>,
+    // brightness yablo wrote last, anything else is a manual change
+    written: Vec<(String, u32)>,
+    paused: bool,
+}
+
+pub fn get_backlights() -> Vec<String> {
+    list_paths(BACKLIGHT_PATH)
+        .into_iter()
+        .filter(|device| std::fs::metadata(format!("{}/brightness", device)).is_ok())
+        .collect()
+}
+
+pub fn get_brightness(device: &str) -> Option<u32> {
+    read_value(&format!("{}/brightness", device))
+}
+
+fn get_max_brightness(device: &str) -> Option<u32> {
+    read_value(&format!("{}/max_brightness", device))
+}
+
+fn set_brightness(device: &str, value: u32) -> bool {
+    match std::fs::write(format!("{}/brightness", device), value.to_string()) {
+        Ok(_) => true,
+        Err(x) => {
+            eprintln!(
+                "[{}] Warning: Can't set brightness of '{}': {}",
+                "!".yellow(),
+                device,
+                x
+            );
+            false
+        }
+    }
+}
+
+impl BacklightState {
+    fn check_manual_change(&mut self) {
+        if self.paused {
+            return;
+        }
+        if self
+            .written
+            .iter()
+            .any(|(device, value)| get_brightness(device) != Some(*value))
+        {
+            self.paused = true;
+            println!(
+                "[{}] Brightness changed manually, backlight dimming paused",
+                "!".yellow()
+            );
+        }
+    }
+
+    pub fn forget_writes(&mut self) {
+        self.written.clear();
+    }
+
+    /*
+        Dims all backlights to the given percentage of their maximum brightness,
+        backlights already darker than that are left alone
+    */
+    pub fn update(&mut self, percent: Option<u8>, power_transition: bool) {
+        self.update_devices(get_backlights, percent, power_transition);
+    }
+
+    fn update_devices(
+        &mut self,
+        devices: impl Fn() -> Vec<String>,
+        percent: Option<u8>,
+        power_transition: bool,
+    ) {
+        self.check_manual_change();
+        if power_transition {
+            if !self.paused {
+                for (device, value) in self.saved.iter() {
+                    if set_brightness(device, *value) {
+                        println!("[{}] Brightness restored", "+".dark_green());
+                    }
+                }
+            }
+            self.saved.clear();
+            self.written.clear();
+            self.paused = false;
+        }
+        let percent = match percent {
+            Some(percent) if !self.paused => percent,
+            _ => return,
+        };
+        for device in devices() {
+            let (current, max) = match (get_brightness(&device), get_max_brightness(&device)) {
+                (Some(current), Some(max)) => (current, max),
+                _ => continue,
+            };
+            let target = (max as u64 * percent as u64 / 100).max(1) as u32;
+            if current <= target {
+                continue;
+            }
+            if !self.saved.iter().any(|(saved, _)| *saved == device) {
+                self.saved.push((device.clone(), current));
+            }
+            if set_brightness(&device, target) {
+                self.written.retain(|(written, _)| *written != device);
+                self.written.push((device, target));
+                println!("[{}] Brightness dimmed to {}%", "+".dark_green(), percent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backlight(brightness: u32, max_brightness: u32) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let device = dir.path().join("intel_backlight");
+        std::fs::create_dir(&device).unwrap();
+        std::fs::write(device.join("brightness"), format!("{}\n", brightness)).unwrap();
+        std::fs::write(
+            device.join("max_brightness"),
+            format!("{}\n", max_brightness),
+        )
+        .unwrap();
+        let device = device.to_str().unwrap().to_string();
+        (dir, device)
+    }
+
+    #[test]
+    fn dims_and_restores() {
+        let (_dir, device) = backlight(800, 1000);
+        let devices = || vec![device.clone()];
+        let mut state = BacklightState::default();
+        state.update_devices(devices, Some(40), true);
+        assert_eq!(get_brightness(&device), Some(400));
+        // already darker
+        state.update_devices(devices, Some(60), false);
+        assert_eq!(get_brightness(&device), Some(400));
+        state.update_devices(devices, None, true);
+        assert_eq!(get_brightness(&device), Some(800));
+        // never off
+        state.update_devices(devices, Some(0), true);
+        assert_eq!(get_brightness(&device), Some(1));
+    }
+
+    #[test]
+    fn manual_change_pauses_until_transition() {
+        let (_dir, device) = backlight(800, 1000);
+        let devices = || vec![device.clone()];
+        let mut state = BacklightState::default();
+        state.update_devices(devices, Some(40), true);
+        set_brightness(&device, 700);
+        state.update_devices(devices, Some(20), false);
+        assert!(state.paused);
+        assert_eq!(get_brightness(&device), Some(700));
+        // the manual brightness is kept on the next transition
+        state.update_devices(devices, None, true);
+        assert!(!state.paused);
+        assert_eq!(get_brightness(&device), Some(700));
+        state.update_devices(devices, Some(50), true);
+        assert_eq!(get_brightness(&device), Some(500));
+        assert!(!state.paused);
+    }
+}
@@ -0,0 +1,728 @@
+use crate::snapshot::Snapshot;
+use crossterm::style::Stylize;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedObjectPath, Value};
+
+/*
+    CPU usage of cgroup v2 slices and services from cpu.stat, so that
+    background work can be left out of the load used for stage decisions,
+    and CPU limits for them on battery. CPUQuota= and CPUWeight= are set
+    through systemd, only cpu.uclamp.max is written directly
+*/
+
+pub const CGROUP_PATH: &str = "/sys/fs/cgroup";
+
+// period of the first loadavg value in seconds
+const LOADAVG_PERIOD: f32 = 60.0;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LoadConfig {
+    // cgroups left out of the load completely, e.g. "background.slice"
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // cgroups counted with a factor between 0 and 1
+    #[serde(default)]
+    pub weights: BTreeMap<String, f32>,
+}
+
+/*
+    CPU time in microseconds, as in cpu.stat
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CpuUsage {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+}
+
+/*
+    Load of a set of cgroups over an interval, busy is the number of CPUs
+    kept busy, user the share of the total CPU time spent in user mode in
+    percent like loadperc
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CgroupLoad {
+    pub busy: f32,
+    pub user: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct CgroupMeter {
+    last_sample: Option<(std::time::Instant, BTreeMap<String, CpuUsage>)>,
+    // seconds covered by the last sample
+    interval: f32,
+    // averaged busy CPUs of the discount
+    busy: Option<f32>,
+}
+
+pub fn is_cgroup_v2() -> bool {
+    std::path::Path::new(&format!("{}/cgroup.controllers", CGROUP_PATH)).exists()
+}
+
+/*
+    Paths are relative to the cgroup root, a leading or trailing '/' is
+    ignored
+*/
+pub fn normalize_path(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+pub fn parse_cpu_stat(stat: &str) -> Option<CpuUsage> {
+    let mut usage = None;
+    let mut user = None;
+    for line in stat.lines() {
+        let (key, value) = match line.split_once(' ') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match key {
+            "usage_usec" => usage = value.trim().parse::<u64>().ok(),
+            "user_usec" => user = value.trim().parse::<u64>().ok(),
+            _ => (),
+        }
+    }
+    Some(CpuUsage {
+        usage_usec: usage?,
+        user_usec: user?,
+    })
+}
+
+pub fn get_cpu_usage(path: &str) -> Option<CpuUsage> {
+    let stat = std::fs::read_to_string(format!("{}/{}/cpu.stat", CGROUP_PATH, path)).ok()?;
+    parse_cpu_stat(&stat)
+}
+
+impl LoadConfig {
+    /*
+        Excluded cgroups have a weight of 0
+    */
+    pub fn get_weights(&self) -> BTreeMap<String, f32> {
+        let mut weights: BTreeMap<String, f32> = self
+            .weights
+            .iter()
+            .map(|(path, weight)| (normalize_path(path), *weight))
+            .collect();
+        for path in self.exclude.iter() {
+            weights.insert(normalize_path(path), 0.0);
+        }
+        weights
+    }
+}
+
+fn is_below(path: &str, parent: &str) -> bool {
+    path.len() > parent.len() && path.starts_with(parent) && path[parent.len()..].starts_with('/')
+}
+
+/*
+    The part of the usage to leave out of the load. Nested cgroups keep their
+    own weight: a parent's usage is counted without the usage of configured
+    cgroups below it
+*/
+pub fn get_discount(
+    weights: &BTreeMap<String, f32>,
+    usage: &BTreeMap<String, CgroupLoad>,
+) -> CgroupLoad {
+    let mut discount = CgroupLoad::default();
+    for (path, weight) in weights.iter() {
+        let load = match usage.get(path) {
+            Some(load) => *load,
+            None => continue,
+        };
+        // only the closest configured cgroups below are subtracted
+        let (busy, user) = usage
+            .iter()
+            .filter(|(child, _)| is_below(child, path))
+            .filter(|(child, _)| {
+                !usage
+                    .keys()
+                    .any(|between| is_below(between, path) && is_below(child, between))
+            })
+            .fold((load.busy, load.user), |(busy, user), (_, child)| {
+                (busy - child.busy, user - child.user)
+            });
+        let factor = 1.0 - weight.clamp(0.0, 1.0);
+        discount.busy += busy.max(0.0) * factor;
+        discount.user += user.max(0.0) * factor;
+    }
+    discount
+}
+
+impl CgroupMeter {
+    /*
+        Returns the load of each cgroup since the last sample, cgroups that
+        don't exist (any more) are left out. The user share is relative to
+        the online CPUs like loadperc
+    */
+    pub fn sample(
+        &mut self,
+        paths: &[String],
+        cpus: usize,
+    ) -> Option<BTreeMap<String, CgroupLoad>> {
+        let sample = paths
+            .iter()
+            .filter_map(|path| Some((path.clone(), get_cpu_usage(path)?)))
+            .collect();
+        self.add_sample(std::time::Instant::now(), sample, cpus)
+    }
+
+    fn add_sample(
+        &mut self,
+        time: std::time::Instant,
+        sample: BTreeMap<String, CpuUsage>,
+        cpus: usize,
+    ) -> Option<BTreeMap<String, CgroupLoad>> {
+        let last = self.last_sample.replace((time, sample.clone()));
+        let (last_time, last_sample) = last?;
+        let usec = time.duration_since(last_time).as_micros() as f32;
+        if usec <= 0.0 {
+            return None;
+        }
+        self.interval = usec / 1e6;
+        let cpus = cpus.max(1) as f32;
+        Some(
+            sample
+                .iter()
+                .filter_map(|(path, after)| {
+                    let before = last_sample.get(path)?;
+                    Some((
+                        path.clone(),
+                        CgroupLoad {
+                            busy: after.usage_usec.saturating_sub(before.usage_usec) as f32 / usec,
+                            user: after.user_usec.saturating_sub(before.user_usec) as f32
+                                / usec
+                                / cpus
+                                * 100.0,
+                        },
+                    ))
+                })
+                .collect(),
+        )
+    }
+
+    /*
+        loadavg is a one minute exponential moving average, so the busy CPUs
+        are averaged the same way before they are subtracted from it.
+        loadperc is current, the user share is used as sampled
+    */
+    pub fn smooth(&mut self, discount: CgroupLoad) -> CgroupLoad {
+        let busy = match self.busy {
+            Some(average) => {
+                let decay = (-self.interval / LOADAVG_PERIOD).exp();
+                average * decay + discount.busy * (1.0 - decay)
+            }
+            None => discount.busy,
+        };
+        self.busy = Some(busy);
+        CgroupLoad { busy, ..discount }
+    }
+}
+
+/*
+    Limits for a cgroup while on battery, CPUQuota= and CPUWeight= of its
+    unit and cpu.uclamp.max
+*/
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CgroupThrottle {
+    pub cgroup: String,
+    // percent of one CPU, like CPUQuota= of systemd
+    pub cpu_max: Option<u32>,
+    pub cpu_weight: Option<u32>,
+    // percent of the highest operating point
+    pub uclamp_max: Option<f32>,
+}
+
+// CPUQuota= and CPUWeight= not set, as systemd reports it over D-Bus
+pub const UNSET: u64 = u64::MAX;
+
+// kernel default, restored for cgroups that didn't exist at startup
+const DEFAULT_UCLAMP_MAX: &str = "max";
+
+static SYSTEMD: OnceLock<Option<Proxy<'static>>> = OnceLock::new();
+
+fn get_systemd_proxy() -> Option<&'static Proxy<'static>> {
+    SYSTEMD
+        .get_or_init(|| {
+            let connection = Connection::system().ok()?;
+            zbus::blocking::proxy::Builder::new(&connection)
+                .destination("org.freedesktop.systemd1")
+                .ok()?
+                .path("/org/freedesktop/systemd1")
+                .ok()?
+                .interface("org.freedesktop.systemd1.Manager")
+                .ok()?
+                .cache_properties(zbus::proxy::CacheProperties::No)
+                .build()
+                .ok()
+        })
+        .as_ref()
+}
+
+pub fn is_cgroup_unit(unit: &str) -> bool {
+    get_unit_interface(unit).is_some()
+}
+
+pub fn has_systemd() -> bool {
+    get_systemd_proxy().is_some()
+}
+
+/*
+    The cgroup properties are on the interface of the unit type
+*/
+fn get_unit_interface(unit: &str) -> Option<&'static str> {
+    match unit.rsplit_once('.')?.1 {
+        "slice" => Some("org.freedesktop.systemd1.Slice"),
+        "service" => Some("org.freedesktop.systemd1.Service"),
+        "scope" => Some("org.freedesktop.systemd1.Scope"),
+        "socket" => Some("org.freedesktop.systemd1.Socket"),
+        "mount" => Some("org.freedesktop.systemd1.Mount"),
+        "swap" => Some("org.freedesktop.systemd1.Swap"),
+        _ => None,
+    }
+}
+
+fn get_manager() -> zbus::Result<&'static Proxy<'static>> {
+    get_systemd_proxy().ok_or_else(|| zbus::Error::Failure("no connection to systemd".to_string()))
+}
+
+/*
+    Fails for units systemd hasn't loaded, those are throttled once they are
+*/
+pub fn get_unit_property(unit: &str, property: &str) -> zbus::Result<u64> {
+    let manager = get_manager()?;
+    let interface = get_unit_interface(unit)
+        .ok_or_else(|| zbus::Error::Failure(format!("'{}' isn't a unit with a cgroup", unit)))?;
+    let path: OwnedObjectPath = manager.call("GetUnit", &(unit,))?;
+    zbus::blocking::proxy::Builder::<Proxy>::new(manager.connection())
+        .destination("org.freedesktop.systemd1")?
+        .path(path)?
+        .interface(interface)?
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()?
+        .get_property(property)
+}
+
+/*
+    Runtime only, like all of yablo's changes the setting is gone after a
+    reboot
+*/
+pub fn set_unit_property(unit: &str, property: &str, value: u64) -> zbus::Result<()> {
+    get_manager()?.call(
+        "SetUnitProperties",
+        &(unit, true, vec![(property, Value::from(value))]),
+    )
+}
+
+impl CgroupThrottle {
+    /*
+        The unit of the cgroup is the last part of its path
+    */
+    pub fn get_unit(&self) -> String {
+        normalize_path(&self.cgroup)
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /*
+        systemd stores CPUQuota= as microseconds per second
+    */
+    pub fn get_properties(&self) -> Vec<(&'static str, u64)> {
+        let mut properties = Vec::new();
+        if let Some(cpu_max) = self.cpu_max {
+            properties.push(("CPUQuotaPerSecUSec", cpu_max as u64 * 10_000));
+        }
+        if let Some(cpu_weight) = self.cpu_weight {
+            properties.push(("CPUWeight", cpu_weight as u64));
+        }
+        properties
+    }
+
+    /*
+        Files with the values to write and their kernel defaults, formatted
+        like the kernel reads them back: 100% is "max"
+    */
+    pub fn get_values(&self) -> Vec<(String, String, &'static str)> {
+        let path = format!("{}/{}", CGROUP_PATH, normalize_path(&self.cgroup));
+        let mut values = Vec::new();
+        if let Some(uclamp_max) = self.uclamp_max {
+            values.push((
+                format!("{}/cpu.uclamp.max", path),
+                if uclamp_max >= 100.0 {
+                    DEFAULT_UCLAMP_MAX.to_string()
+                } else {
+                    format!("{:.2}", uclamp_max)
+                },
+                DEFAULT_UCLAMP_MAX,
+            ));
+        }
+        values
+    }
+}
+
+pub fn save_throttles(throttles: &[CgroupThrottle], snapshot: &mut Snapshot) {
+    for throttle in throttles.iter() {
+        let unit = throttle.get_unit();
+        for (property, _) in throttle.get_properties() {
+            let value = get_unit_property(&unit, property).unwrap_or(UNSET);
+            snapshot.save_unit_property(&unit, property, value);
+        }
+    }
+    for (path, _, default) in throttles.iter().flat_map(|throttle| throttle.get_values()) {
+        if std::path::Path::new(&path).exists() {
+            snapshot.save(&path);
+        } else {
+            snapshot.save_value(&path, default);
+        }
+    }
+}
+
+/*
+    Called on every run, so units loaded later are throttled as well.
+    On AC only limits still set to yablo's values are reverted, limits
+    changed in the meantime stay. Units systemd hasn't loaded, missing
+    cgroups and cpu.uclamp.max on kernels without uclamp are skipped
+*/
+pub fn set_throttles(throttles: &[CgroupThrottle], on_battery: bool, snapshot: &Snapshot) {
+    for throttle in throttles.iter() {
+        let unit = throttle.get_unit();
+        for (property, value) in throttle.get_properties() {
+            let current = match get_unit_property(&unit, property) {
+                Ok(current) => current,
+                Err(_) => continue,
+            };
+            let target = if on_battery {
+                value
+            } else if current == value {
+                snapshot
+                    .original_unit_property(&unit, property)
+                    .unwrap_or(UNSET)
+            } else {
+                continue;
+            };
+            if current == target {
+                continue;
+            }
+            if let Err(x) = set_unit_property(&unit, property, target) {
+                eprintln!(
+                    "[{}] Warning: Can't set {} of '{}': {}",
+                    "!".yellow(),
+                    property,
+                    unit,
+                    x
+                );
+            }
+        }
+    }
+    for (path, value, _) in throttles.iter().flat_map(|throttle| throttle.get_values()) {
+        let value = if on_battery {
+            Some(value.as_str())
+        } else {
+            match std::fs::read_to_string(&path) {
+                Ok(current) if current.trim_end() == value => None,
+                _ => continue,
+            }
+        };
+        match snapshot.write_or_restore(&path, value) {
+            Ok(_) => (),
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => (),
+            Err(x) => eprintln!("[{}] Warning: Can't write '{}': {}", "!".yellow(), path, x),
+        }
+    }
+}
+
+/*
+    Throttles are tested against a fake systemd on a private dbus-daemon,
+    which is used as the system bus and skipped without dbus-daemon
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testbus::Bus;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use zbus::zvariant::{ObjectPath, OwnedValue};
+
+    const SLICE_PATH: &str = "/org/freedesktop/systemd1/unit/background_2eslice";
+
+    // (property, value) of background.slice and the runtime flags of the calls
+    #[derive(Default)]
+    struct Unit {
+        properties: Vec<(String, u64)>,
+        runtime: Vec<bool>,
+    }
+
+    impl Unit {
+        fn get(&self, property: &str) -> u64 {
+            self.properties
+                .iter()
+                .find(|(p, _)| p == property)
+                .map_or(UNSET, |(_, value)| *value)
+        }
+
+        fn set(&mut self, property: &str, value: u64) {
+            self.properties.retain(|(p, _)| p != property);
+            self.properties.push((property.to_string(), value));
+        }
+    }
+
+    struct FakeManager {
+        unit: Arc<Mutex<Unit>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.systemd1.Manager")]
+    impl FakeManager {
+        fn get_unit(&self, name: &str) -> zbus::fdo::Result<OwnedObjectPath> {
+            match name {
+                "background.slice" => Ok(ObjectPath::try_from(SLICE_PATH).unwrap().into()),
+                _ => Err(zbus::fdo::Error::Failed(format!(
+                    "Unit {} not loaded.",
+                    name
+                ))),
+            }
+        }
+
+        fn set_unit_properties(
+            &self,
+            name: &str,
+            runtime: bool,
+            properties: Vec<(String, OwnedValue)>,
+        ) -> zbus::fdo::Result<()> {
+            self.get_unit(name)?;
+            let mut unit = self.unit.lock().unwrap();
+            unit.runtime.push(runtime);
+            for (property, value) in properties {
+                let value = u64::try_from(value)
+                    .map_err(|x| zbus::fdo::Error::InvalidArgs(x.to_string()))?;
+                unit.set(&property, value);
+            }
+            Ok(())
+        }
+    }
+
+    struct FakeSlice {
+        unit: Arc<Mutex<Unit>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.systemd1.Slice")]
+    impl FakeSlice {
+        #[zbus(property, name = "CPUWeight")]
+        fn cpu_weight(&self) -> u64 {
+            self.unit.lock().unwrap().get("CPUWeight")
+        }
+
+        #[zbus(property, name = "CPUQuotaPerSecUSec")]
+        fn cpu_quota_per_sec_usec(&self) -> u64 {
+            self.unit.lock().unwrap().get("CPUQuotaPerSecUSec")
+        }
+    }
+
+    #[test]
+    fn throttles_through_systemd() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => return eprintln!("dbus-daemon not found, skipped"),
+        };
+        let unit = Arc::new(Mutex::new(Unit::default()));
+        unit.lock().unwrap().set("CPUWeight", 100);
+        let _systemd = bus
+            .connect()
+            .name("org.freedesktop.systemd1")
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/systemd1",
+                FakeManager { unit: unit.clone() },
+            )
+            .unwrap()
+            .serve_at(SLICE_PATH, FakeSlice { unit: unit.clone() })
+            .unwrap()
+            .build()
+            .unwrap();
+        std::env::set_var("DBUS_SYSTEM_BUS_ADDRESS", &bus.address);
+        assert!(has_systemd());
+
+        let throttles = vec![
+            CgroupThrottle {
+                cgroup: "/background.slice/".to_string(),
+                cpu_max: Some(20),
+                cpu_weight: Some(10),
+                uclamp_max: None,
+            },
+            CgroupThrottle {
+                cgroup: "system.slice/missing.service".to_string(),
+                cpu_weight: Some(10),
+                ..Default::default()
+            },
+        ];
+        let mut snapshot = Snapshot::default();
+        save_throttles(&throttles, &mut snapshot);
+        assert_eq!(
+            snapshot.original_unit_property("background.slice", "CPUWeight"),
+            Some(100)
+        );
+        assert_eq!(
+            snapshot.original_unit_property("background.slice", "CPUQuotaPerSecUSec"),
+            Some(UNSET)
+        );
+        assert_eq!(
+            snapshot.original_unit_property("missing.service", "CPUWeight"),
+            Some(UNSET)
+        );
+
+        set_throttles(&throttles, true, &snapshot);
+        assert_eq!(unit.lock().unwrap().get("CPUQuotaPerSecUSec"), 200_000);
+        assert_eq!(unit.lock().unwrap().get("CPUWeight"), 10);
+        // nothing is set again while the values stay
+        set_throttles(&throttles, true, &snapshot);
+        assert_eq!(unit.lock().unwrap().runtime, vec![true, true]);
+
+        // a weight changed in the meantime stays on AC
+        unit.lock().unwrap().set("CPUWeight", 50);
+        set_throttles(&throttles, false, &snapshot);
+        assert_eq!(unit.lock().unwrap().get("CPUQuotaPerSecUSec"), UNSET);
+        assert_eq!(unit.lock().unwrap().get("CPUWeight"), 50);
+
+        set_throttles(&throttles, true, &snapshot);
+        snapshot.restore();
+        assert_eq!(unit.lock().unwrap().get("CPUQuotaPerSecUSec"), UNSET);
+        assert_eq!(unit.lock().unwrap().get("CPUWeight"), 100);
+    }
+
+    #[test]
+    fn formats_uclamp_like_the_kernel() {
+        let throttle = |uclamp_max| CgroupThrottle {
+            cgroup: "background.slice".to_string(),
+            uclamp_max: Some(uclamp_max),
+            ..Default::default()
+        };
+        assert_eq!(throttle(100.0).get_values()[0].1, "max");
+        assert_eq!(throttle(42.5).get_values()[0].1, "42.50");
+        assert_eq!(throttle(0.0).get_values()[0].1, "0.00");
+        assert!(throttle(50.0).get_properties().is_empty());
+    }
+
+    fn cpu_stat(usage_usec: u64, user_usec: u64) -> CpuUsage {
+        parse_cpu_stat(&format!(
+            "usage_usec {}\nuser_usec {}\nsystem_usec {}\nnr_periods 0\n",
+            usage_usec,
+            user_usec,
+            usage_usec - user_usec
+        ))
+        .unwrap()
+    }
+
+    fn loads(loads: &[(&str, f32)]) -> BTreeMap<String, CgroupLoad> {
+        loads
+            .iter()
+            .map(|(path, busy)| {
+                let load = CgroupLoad {
+                    busy: *busy,
+                    user: *busy * 10.0,
+                };
+                (path.to_string(), load)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples_cpu_stat() {
+        let start = std::time::Instant::now();
+        let sample = |stats: &[(&str, CpuUsage)]| {
+            stats
+                .iter()
+                .map(|(path, usage)| (path.to_string(), *usage))
+                .collect::<BTreeMap<String, CpuUsage>>()
+        };
+        let mut meter = CgroupMeter::default();
+        let first = sample(&[("background.slice", cpu_stat(1_000_000, 600_000))]);
+        assert_eq!(meter.add_sample(start, first, 4), None);
+        // 1s of CPU time, half of it in user mode, over 2s on 4 CPUs
+        let second = sample(&[
+            ("background.slice", cpu_stat(2_000_000, 1_100_000)),
+            ("user.slice", cpu_stat(500_000, 500_000)),
+        ]);
+        let time = start + std::time::Duration::from_secs(2);
+        let load = meter.add_sample(time, second, 4).unwrap();
+        assert_eq!(
+            load.get("background.slice"),
+            Some(&CgroupLoad {
+                busy: 0.5,
+                user: 6.25
+            })
+        );
+        // new cgroups have no usage to compare with yet
+        assert_eq!(load.get("user.slice"), None);
+        assert_eq!(parse_cpu_stat("usage_usec 100\n"), None);
+    }
+
+    #[test]
+    fn exclude_is_weight_zero() {
+        let excluded = LoadConfig {
+            exclude: vec!["/background.slice/".to_string()],
+            ..Default::default()
+        };
+        let weighted = LoadConfig {
+            weights: vec![("background.slice".to_string(), 0.0)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(excluded.get_weights(), weighted.get_weights());
+        let usage = loads(&[("background.slice", 1.5)]);
+        let discount = get_discount(&excluded.get_weights(), &usage);
+        assert_eq!(discount, usage["background.slice"]);
+        // exclude wins over a weight of the same cgroup
+        let both = LoadConfig {
+            exclude: vec!["background.slice".to_string()],
+            weights: vec![("background.slice".to_string(), 0.5)]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(both.get_weights()["background.slice"], 0.0);
+    }
+
+    #[test]
+    fn nested_cgroups_keep_their_weight() {
+        let weights: BTreeMap<String, f32> = vec![
+            ("user.slice".to_string(), 0.5),
+            ("user.slice/user-1000.slice/app.slice".to_string(), 0.0),
+        ]
+        .into_iter()
+        .collect();
+        let usage = loads(&[
+            ("user.slice", 3.0),
+            ("user.slice/user-1000.slice/app.slice", 1.0),
+            ("user.slice-other", 2.0),
+        ]);
+        // (3 - 1) * 0.5 for the parent, 1 for the child
+        assert_eq!(get_discount(&weights, &usage).busy, 2.0);
+        // only the closest configured cgroup below is subtracted
+        let weights: BTreeMap<String, f32> = ["a", "a/b", "a/b/c"]
+            .iter()
+            .map(|path| (path.to_string(), 0.0))
+            .collect();
+        let usage = loads(&[("a", 3.0), ("a/b", 2.0), ("a/b/c", 1.0)]);
+        assert_eq!(get_discount(&weights, &usage).busy, 3.0);
+        // a child can't make the parent negative
+        let usage = loads(&[("a", 1.0), ("a/b", 2.0)]);
+        assert_eq!(get_discount(&weights, &usage).busy, 2.0);
+    }
+
+    #[test]
+    fn smooths_like_loadavg() {
+        let mut meter = CgroupMeter {
+            interval: LOADAVG_PERIOD,
+            ..Default::default()
+        };
+        let load = |busy| CgroupLoad { busy, user: 50.0 };
+        assert_eq!(meter.smooth(load(2.0)), load(2.0));
+        // after one period the old value has decayed to 1/e
+        let smoothed = meter.smooth(load(0.0));
+        assert!((smoothed.busy - 2.0 / std::f32::consts::E).abs() < 1e-6);
+        assert_eq!(smoothed.user, 50.0);
+        let smoothed = meter.smooth(load(2.0));
+        assert!(smoothed.busy > 2.0 / std::f32::consts::E && smoothed.busy < 2.0);
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 
 pub fn build_cli() -> App<'static, 'static> {
     App::new("yablo")
@@ -45,4 +45,40 @@ pub fn build_cli() -> App<'static, 'static> {
                 .help("Reloads the systemd daemon")
                 .takes_value(false),
         )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Overrides the automatic profile selection")
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Uses a profile until it expires or the power source changes")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Profile from the config file")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("for")
+                                .long("for")
+                                .help("Expires after a duration, e.g. 30m, 2h or 1h30m")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("clear").about("Restores the automatic profile selection"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("runtime")
+                .about("Adjusts the settings on battery to reach a runtime")
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Sets how long the battery has to last from now on")
+                        .arg(
+                            Arg::with_name("duration")
+                                .help("Runtime, e.g. 5h or 4h30m")
+                                .required(true),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("clear").about("Removes the runtime target")),
+        )
 }
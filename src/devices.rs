@@ -0,0 +1,249 @@
+use crate::snapshot::Snapshot;
+use crate::sysfs::{list_paths, read_trimmed};
+use crossterm::style::Stylize;
+use serde_derive::Deserialize;
+
+/*
+    Runtime power management of peripherals: PCIe ASPM, PCI runtime PM,
+    USB autosuspend and SATA link power management
+*/
+
+const ASPM_POLICY: &str = "/sys/module/pcie_aspm/parameters/policy";
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+const USB_DEVICES: &str = "/sys/bus/usb/devices";
+const SCSI_HOSTS: &str = "/sys/class/scsi_host";
+
+pub const ALPM_POLICIES: [&str; 4] = [
+    "max_performance",
+    "medium_power",
+    "med_power_with_dipm",
+    "min_power",
+];
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DevicePowerConfig {
+    pub pcie_aspm: Option<String>,
+    pub runtime_pm: Option<bool>,
+    pub usb_autosuspend: Option<bool>,
+    pub sata_alpm: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DeviceDenyList {
+    #[serde(default)]
+    pub runtime_pm_deny: Vec<String>,
+    #[serde(default)]
+    pub usb_autosuspend_deny: Vec<String>,
+    #[serde(default)]
+    pub usb_autosuspend_input: bool,
+}
+
+/*
+    The policy parameter lists all choices with the active one in brackets,
+    e.g. "default performance [powersave] powersupersave"
+*/
+pub fn get_aspm_policies() -> Option<(Vec<String>, String)> {
+    let content = read_trimmed(ASPM_POLICY)?;
+    let policies: Vec<String> = content
+        .split_whitespace()
+        .map(|policy| policy.trim_matches(|c| c == '[' || c == ']').to_string())
+        .collect();
+    let current = content
+        .split_whitespace()
+        .find(|policy| policy.starts_with('['))?
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string();
+    Some((policies, current))
+}
+
+fn get_pci_id(device: &str) -> Option<String> {
+    let vendor = read_trimmed(&format!("{}/vendor", device))?;
+    let product = read_trimmed(&format!("{}/device", device))?;
+    Some(format!(
+        "{}:{}",
+        vendor.trim_start_matches("0x"),
+        product.trim_start_matches("0x")
+    ))
+}
+
+fn get_usb_id(device: &str) -> Option<String> {
+    let vendor = read_trimmed(&format!("{}/idVendor", device))?;
+    let product = read_trimmed(&format!("{}/idProduct", device))?;
+    Some(format!("{}:{}", vendor, product))
+}
+
+fn is_usb_input_device(device: &str) -> bool {
+    list_paths(device).iter().any(|interface| {
+        read_trimmed(&format!("{}/bInterfaceClass", interface)).as_deref() == Some("03")
+    })
+}
+
+fn is_denied(id: &str, deny_list: &[String]) -> bool {
+    deny_list
+        .iter()
+        .any(|denied| denied.eq_ignore_ascii_case(id))
+}
+
+fn get_runtime_pm_paths(pci_devices: &str, deny: &DeviceDenyList) -> Vec<String> {
+    list_paths(pci_devices)
+        .iter()
+        .filter(|device| match get_pci_id(device) {
+            Some(id) => !is_denied(&id, &deny.runtime_pm_deny),
+            None => false,
+        })
+        .map(|device| format!("{}/power/control", device))
+        .filter(|path| std::fs::metadata(path).is_ok())
+        .collect()
+}
+
+fn get_usb_autosuspend_paths(usb_devices: &str, deny: &DeviceDenyList) -> Vec<String> {
+    list_paths(usb_devices)
+        .iter()
+        .filter(|device| match get_usb_id(device) {
+            Some(id) => !is_denied(&id, &deny.usb_autosuspend_deny),
+            None => false,
+        })
+        .filter(|device| deny.usb_autosuspend_input || !is_usb_input_device(device))
+        .map(|device| format!("{}/power/control", device))
+        .filter(|path| std::fs::metadata(path).is_ok())
+        .collect()
+}
+
+fn get_alpm_paths() -> Vec<String> {
+    list_paths(SCSI_HOSTS)
+        .iter()
+        .map(|host| format!("{}/link_power_management_policy", host))
+        .filter(|path| std::fs::metadata(path).is_ok())
+        .collect()
+}
+
+pub fn check_alpm_availability() -> bool {
+    !get_alpm_paths().is_empty()
+}
+
+pub fn save_device_states(deny: &DeviceDenyList, snapshot: &mut Snapshot) {
+    if let Some((_, current)) = get_aspm_policies() {
+        snapshot.save_value(ASPM_POLICY, &current);
+    }
+    for path in get_runtime_pm_paths(PCI_DEVICES, deny)
+        .iter()
+        .chain(get_usb_autosuspend_paths(USB_DEVICES, deny).iter())
+        .chain(get_alpm_paths().iter())
+    {
+        snapshot.save(path);
+    }
+}
+
+fn write_device_state(path: &str, value: Option<&str>, snapshot: &Snapshot) {
+    match snapshot.write_or_restore(path, value) {
+        Ok(_) => (),
+        // devices may disappear at any time, e.g. unplugged USB devices
+        Err(x) => eprintln!("[{}] Warning: Can't write '{}': {}", "!".yellow(), path, x),
+    }
+}
+
+/*
+    Devices without a setting for the current power source get their state
+    from the startup snapshot back
+*/
+pub fn set_device_power(config: &DevicePowerConfig, deny: &DeviceDenyList, snapshot: &Snapshot) {
+    if let Some((_, current)) = get_aspm_policies() {
+        let policy = config
+            .pcie_aspm
+            .as_deref()
+            .or_else(|| snapshot.original(ASPM_POLICY));
+        match policy {
+            Some(policy) if policy != current => {
+                write_device_state(ASPM_POLICY, Some(policy), snapshot)
+            }
+            _ => (),
+        }
+    }
+    let runtime_pm = config
+        .runtime_pm
+        .map(|enabled| if enabled { "auto" } else { "on" });
+    for path in get_runtime_pm_paths(PCI_DEVICES, deny) {
+        write_device_state(&path, runtime_pm, snapshot);
+    }
+    let usb_autosuspend = config
+        .usb_autosuspend
+        .map(|enabled| if enabled { "auto" } else { "on" });
+    for path in get_usb_autosuspend_paths(USB_DEVICES, deny) {
+        write_device_state(&path, usb_autosuspend, snapshot);
+    }
+    for path in get_alpm_paths() {
+        write_device_state(&path, config.sata_alpm.as_deref(), snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(sysfs: &std::path::Path, file: &str, content: &str) {
+        let path = sysfs.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn names(paths: Vec<String>) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.rsplit('/').nth(2).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn pci_deny_list() {
+        let sysfs = tempfile::tempdir().unwrap();
+        for (device, vendor, product) in [
+            ("0000:00:02.0", "0x8086", "0x9a49"),
+            ("0000:00:14.0", "0x8086", "0xa0ed"),
+            ("0000:01:00.0", "0x10de", "0x1f95"),
+        ]
+        .iter()
+        {
+            create(sysfs.path(), &format!("{}/vendor", device), vendor);
+            create(sysfs.path(), &format!("{}/device", device), product);
+            create(sysfs.path(), &format!("{}/power/control", device), "on");
+        }
+        // no runtime PM
+        std::fs::remove_file(sysfs.path().join("0000:00:14.0/power/control")).unwrap();
+        let deny = DeviceDenyList {
+            runtime_pm_deny: vec!["10DE:1F95".to_string()],
+            ..Default::default()
+        };
+        let paths = get_runtime_pm_paths(sysfs.path().to_str().unwrap(), &deny);
+        assert_eq!(names(paths), vec!["0000:00:02.0"]);
+    }
+
+    #[test]
+    fn usb_deny_list_and_input_devices() {
+        let sysfs = tempfile::tempdir().unwrap();
+        for (device, id, class) in [
+            ("1-1", "046d:c52b", "03"),
+            ("1-2", "0bda:8153", "ff"),
+            ("1-3", "8087:0026", "e0"),
+        ]
+        .iter()
+        {
+            let (vendor, product) = id.split_once(':').unwrap();
+            create(sysfs.path(), &format!("{}/idVendor", device), vendor);
+            create(sysfs.path(), &format!("{}/idProduct", device), product);
+            create(sysfs.path(), &format!("{}/power/control", device), "on");
+            let interface = format!("{}/{}:1.0/bInterfaceClass", device, device);
+            create(sysfs.path(), &interface, class);
+        }
+        let usb_devices = sysfs.path().to_str().unwrap();
+        let mut deny = DeviceDenyList {
+            usb_autosuspend_deny: vec!["8087:0026".to_string()],
+            ..Default::default()
+        };
+        let paths = get_usb_autosuspend_paths(usb_devices, &deny);
+        assert_eq!(names(paths), vec!["1-2"]);
+        deny.usb_autosuspend_input = true;
+        let paths = get_usb_autosuspend_paths(usb_devices, &deny);
+        assert_eq!(names(paths), vec!["1-1", "1-2"]);
+        assert!(!is_denied("8087:0026", &["8087".to_string()]));
+    }
+}
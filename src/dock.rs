@@ -0,0 +1,114 @@
+use crate::idle;
+use crate::sysfs::{list_dir, read_trimmed};
+use std::os::unix::io::AsRawFd;
+
+/*
+    Lid and dock state: the ACPI lid button, logind or the SW_LID input
+    switch, connected external displays and known dock USB devices
+*/
+
+const ACPI_LID_PATH: &str = "/proc/acpi/button/lid";
+const DRM_PATH: &str = "/sys/class/drm";
+const INPUT_PATH: &str = "/sys/class/input";
+const USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+// connectors of built-in panels
+const INTERNAL_CONNECTORS: [&str; 3] = ["eDP", "LVDS", "DSI"];
+
+const SW_LID: usize = 0x00;
+const SW_CNT: usize = 0x11;
+
+nix::ioctl_read_buf!(eviocgsw, b'E', 0x1b, u8);
+
+/*
+    e.g. "state:      closed"
+*/
+fn get_acpi_lid_closed() -> Option<bool> {
+    list_dir(ACPI_LID_PATH).iter().find_map(|lid| {
+        let state = read_trimmed(&format!("{}/{}/state", ACPI_LID_PATH, lid))?;
+        match state.split_whitespace().last()? {
+            "closed" => Some(true),
+            "open" => Some(false),
+            _ => None,
+        }
+    })
+}
+
+/*
+    The switch capabilities are a hex bitmask, SW_LID is bit 0
+*/
+fn has_lid_switch(event: &str) -> bool {
+    match read_trimmed(&format!("{}/{}/device/capabilities/sw", INPUT_PATH, event)) {
+        Some(mask) => mask
+            .split_whitespace()
+            .last()
+            .and_then(|word| u64::from_str_radix(word, 16).ok())
+            .is_some_and(|word| word & (1 << SW_LID) != 0),
+        None => false,
+    }
+}
+
+/*
+    logind reads the lid switch itself, which also works with
+    PrivateDevices=true hiding /dev/input from yablo.service
+*/
+fn get_logind_lid_closed() -> Option<bool> {
+    idle::get_logind_proxy()?.get_property("LidClosed").ok()
+}
+
+fn get_input_lid_closed() -> Option<bool> {
+    let event = list_dir(INPUT_PATH)
+        .into_iter()
+        .filter(|event| event.starts_with("event"))
+        .find(|event| has_lid_switch(event))?;
+    let device = std::fs::File::open(format!("/dev/input/{}", event)).ok()?;
+    let mut switches = [0u8; SW_CNT.div_ceil(8)];
+    unsafe { eviocgsw(device.as_raw_fd(), &mut switches) }.ok()?;
+    Some(switches[SW_LID / 8] & (1 << (SW_LID % 8)) != 0)
+}
+
+pub fn get_lid_closed() -> Option<bool> {
+    get_acpi_lid_closed()
+        .or_else(get_logind_lid_closed)
+        .or_else(get_input_lid_closed)
+}
+
+/*
+    Connectors are named card<N>-<type>-<index>, e.g. card0-HDMI-A-1
+*/
+pub fn get_external_displays() -> Vec<String> {
+    list_dir(DRM_PATH)
+        .into_iter()
+        .filter_map(|connector| {
+            let name = connector.split_once('-')?.1.to_string();
+            if INTERNAL_CONNECTORS
+                .iter()
+                .any(|internal| name.starts_with(internal))
+            {
+                return None;
+            }
+            match read_trimmed(&format!("{}/{}/status", DRM_PATH, connector)).as_deref() {
+                Some("connected") => Some(name),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/*
+    Dock ids are either "idVendor" or "idVendor:idProduct"
+*/
+pub fn is_dock_connected(dock_ids: &[String]) -> bool {
+    list_dir(USB_DEVICES).iter().any(|device| {
+        let path = format!("{}/{}", USB_DEVICES, device);
+        let vendor = match read_trimmed(&format!("{}/idVendor", path)) {
+            Some(vendor) => vendor,
+            None => return false,
+        };
+        let product = read_trimmed(&format!("{}/idProduct", path)).unwrap_or_default();
+        let id = format!("{}:{}", vendor, product);
+        dock_ids
+            .iter()
+            .any(|dock| dock.eq_ignore_ascii_case(&vendor) || dock.eq_ignore_ascii_case(&id))
+    })
+}
@@ -0,0 +1,150 @@
+use crate::sysfs::read_trimmed;
+
+/*
+    CPU frequency scaling driver detection and capabilities
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalingDriver {
+    AcpiCpufreq,
+    IntelPstate,
+    IntelCpufreq,
+    AmdPstate,
+    AmdPstateEpp,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TurboInterface {
+    // intel_pstate/no_turbo, inverted logic
+    NoTurbo,
+    // cpufreq/boost
+    Boost,
+}
+
+#[derive(Debug, Clone)]
+pub struct DriverInfo {
+    pub name: String,
+    pub driver: ScalingDriver,
+    pub turbo: Option<TurboInterface>,
+    pub epp: bool,
+    pub mode: Option<String>,
+}
+
+pub const INTEL_PSTATE_STATUS: &str = "/sys/devices/system/cpu/intel_pstate/status";
+pub const AMD_PSTATE_STATUS: &str = "/sys/devices/system/cpu/amd_pstate/status";
+pub const SYSFS_PATH: &str = "/sys";
+// relative to the sysfs root
+pub const NO_TURBO: &str = "devices/system/cpu/intel_pstate/no_turbo";
+pub const BOOST: &str = "devices/system/cpu/cpufreq/boost";
+
+pub const AMD_PSTATE_MODES: [&str; 3] = ["active", "passive", "guided"];
+
+pub fn get_driver_info() -> DriverInfo {
+    let name = read_trimmed("/sys/devices/system/cpu/cpu0/cpufreq/scaling_driver")
+        .unwrap_or_else(|| String::from("none"));
+    let driver = match name.as_str() {
+        "acpi-cpufreq" => ScalingDriver::AcpiCpufreq,
+        "intel_pstate" => ScalingDriver::IntelPstate,
+        "intel_cpufreq" => ScalingDriver::IntelCpufreq,
+        "amd-pstate" => ScalingDriver::AmdPstate,
+        "amd-pstate-epp" => ScalingDriver::AmdPstateEpp,
+        _ => ScalingDriver::Other,
+    };
+    let turbo = get_turbo_interface(SYSFS_PATH);
+    let epp =
+        std::fs::metadata("/sys/devices/system/cpu/cpu0/cpufreq/energy_performance_preference")
+            .is_ok();
+    let mode = match driver {
+        ScalingDriver::IntelPstate | ScalingDriver::IntelCpufreq => {
+            read_trimmed(INTEL_PSTATE_STATUS)
+        }
+        ScalingDriver::AmdPstate | ScalingDriver::AmdPstateEpp => read_trimmed(AMD_PSTATE_STATUS),
+        _ => None,
+    };
+    DriverInfo {
+        name,
+        driver,
+        turbo,
+        epp,
+        mode,
+    }
+}
+
+/*
+    intel_cpufreq is intel_pstate in passive mode and keeps no_turbo, so
+    no_turbo is preferred if both exist
+*/
+pub fn get_turbo_interface(sysfs: &str) -> Option<TurboInterface> {
+    if std::fs::metadata(format!("{}/{}", sysfs, NO_TURBO)).is_ok() {
+        Some(TurboInterface::NoTurbo)
+    } else if std::fs::metadata(format!("{}/{}", sysfs, BOOST)).is_ok() {
+        Some(TurboInterface::Boost)
+    } else {
+        None
+    }
+}
+
+/*
+    Switching the mode re-registers the driver: governors fall back to the
+    default of the new mode and have to be set again afterwards
+*/
+pub fn set_amd_pstate_mode(mode: &str) -> std::io::Result<()> {
+    if read_trimmed(AMD_PSTATE_STATUS).as_deref() == Some(mode) {
+        return Ok(());
+    }
+    std::fs::write(AMD_PSTATE_STATUS, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(sysfs: &std::path::Path, file: &str, content: &str) {
+        let path = sysfs.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn no_turbo_interface() {
+        let sysfs = tempfile::tempdir().unwrap();
+        create(
+            sysfs.path(),
+            "devices/system/cpu/cpu0/cpufreq/scaling_driver",
+            "acpi-cpufreq\n",
+        );
+        assert_eq!(get_turbo_interface(sysfs.path().to_str().unwrap()), None);
+    }
+
+    #[test]
+    fn intel_no_turbo() {
+        let sysfs = tempfile::tempdir().unwrap();
+        create(sysfs.path(), NO_TURBO, "0\n");
+        assert_eq!(
+            get_turbo_interface(sysfs.path().to_str().unwrap()),
+            Some(TurboInterface::NoTurbo)
+        );
+    }
+
+    #[test]
+    fn cpufreq_boost() {
+        let sysfs = tempfile::tempdir().unwrap();
+        create(sysfs.path(), BOOST, "1\n");
+        assert_eq!(
+            get_turbo_interface(sysfs.path().to_str().unwrap()),
+            Some(TurboInterface::Boost)
+        );
+    }
+
+    #[test]
+    fn no_turbo_preferred_over_boost() {
+        let sysfs = tempfile::tempdir().unwrap();
+        create(sysfs.path(), NO_TURBO, "1\n");
+        create(sysfs.path(), BOOST, "0\n");
+        assert_eq!(
+            get_turbo_interface(sysfs.path().to_str().unwrap()),
+            Some(TurboInterface::NoTurbo)
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use crate::rapl;
+use crate::sysfs::{list_paths, read_trimmed, read_value};
+use serde_derive::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+
+/*
+    Energy accounting per applied governor/turbo state using the RAPL
+    energy counters and the battery discharge power
+*/
+
+// written by the daemon on every run, so other modes can show its totals
+pub const STATS_PATH: &str = "/run/yablo/energy.toml";
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnergyState {
+    pub label: String,
+    pub package_wh: f64,
+    pub battery_wh: f64,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct EnergyMeter {
+    pub states: Vec<EnergyState>,
+    last_sample: Option<Sample>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EnergyStats {
+    states: Vec<EnergyState>,
+}
+
+#[derive(Debug)]
+struct Sample {
+    time: std::time::Instant,
+    label: String,
+    counters: Vec<Option<u64>>,
+    battery_power: Option<f64>,
+}
+
+impl EnergyMeter {
+    /*
+        The energy used since the last sample is attributed to the state that was
+        active during that interval, i.e. the label passed with the previous sample
+    */
+    pub fn sample(&mut self, label: String, on_battery: bool) {
+        let sample = Sample {
+            time: std::time::Instant::now(),
+            label,
+            counters: get_energy_counters(),
+            battery_power: if on_battery {
+                get_battery_power()
+            } else {
+                None
+            },
+        };
+        if let Some(last) = self.last_sample.take() {
+            let seconds = sample.time.duration_since(last.time).as_secs_f64();
+            let package_uj: u64 = get_package_zones_max_range()
+                .iter()
+                .zip(last.counters.iter().zip(sample.counters.iter()))
+                .filter_map(|(max, (before, after))| {
+                    Some(counter_delta((*before)?, (*after)?, *max))
+                })
+                .sum();
+            let battery_wh = match (last.battery_power, sample.battery_power) {
+                (Some(before), Some(after)) => (before + after) / 2.0 * seconds / 3600.0,
+                _ => 0.0,
+            };
+            let state = match self.states.iter_mut().find(|s| s.label == last.label) {
+                Some(state) => state,
+                None => {
+                    self.states.push(EnergyState {
+                        label: last.label.clone(),
+                        ..Default::default()
+                    });
+                    self.states.last_mut().unwrap()
+                }
+            };
+            state.package_wh += package_uj as f64 / 3.6e9;
+            state.battery_wh += battery_wh;
+            state.seconds += seconds;
+        }
+        self.last_sample = Some(sample);
+    }
+
+    /*
+        Written to a temporary file first, so readers never see a partial one.
+        Readable by everyone despite UMask=0077 of yablo.service
+    */
+    pub fn write_stats(&self) -> std::io::Result<()> {
+        let content = toml::to_string(&EnergyStats {
+            states: self.states.clone(),
+        })
+        .map_err(|x| std::io::Error::new(std::io::ErrorKind::InvalidData, x))?;
+        let tmp = format!("{}.tmp", STATS_PATH);
+        std::fs::write(&tmp, content)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644))?;
+        std::fs::rename(&tmp, STATS_PATH)
+    }
+}
+
+/*
+    The states accumulated by the running daemon, if there is one
+*/
+pub fn read_stats() -> Option<Vec<EnergyState>> {
+    let stats: EnergyStats = toml::from_str(&std::fs::read_to_string(STATS_PATH).ok()?).ok()?;
+    Some(stats.states)
+}
+
+pub fn remove_stats() -> std::io::Result<()> {
+    match std::fs::remove_file(STATS_PATH) {
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn get_energy_counters() -> Vec<Option<u64>> {
+    rapl::get_package_zones()
+        .iter()
+        .map(|zone| read_value::<u64>(&format!("{}/energy_uj", zone)))
+        .collect()
+}
+
+fn get_package_zones_max_range() -> Vec<u64> {
+    rapl::get_package_zones()
+        .iter()
+        .map(|zone| read_value::<u64>(&format!("{}/max_energy_range_uj", zone)).unwrap_or(u64::MAX))
+        .collect()
+}
+
+/*
+    energy_uj wraps around to zero after max_energy_range_uj. A counter
+    above the range (e.g. a misreported range) counts from the wrap only
+*/
+fn counter_delta(before: u64, after: u64, max_range: u64) -> u64 {
+    if after >= before {
+        after - before
+    } else {
+        max_range.saturating_sub(before).saturating_add(after)
+    }
+}
+
+pub fn get_batteries() -> Vec<String> {
+    list_paths(POWER_SUPPLY_PATH)
+        .into_iter()
+        .filter(|supply| read_trimmed(&format!("{}/type", supply)).as_deref() == Some("Battery"))
+        .collect()
+}
+
+/*
+    Battery discharge power in watts summed over all batteries, either from
+    power_now or computed from current_now and voltage_now. Some drivers
+    report a negative current_now while discharging
+*/
+pub fn get_battery_power() -> Option<f64> {
+    let mut total: Option<f64> = None;
+    for supply in get_batteries() {
+        let power = match read_value::<u64>(&format!("{}/power_now", supply)) {
+            Some(uw) => Some(uw as f64 / 1e6),
+            None => match (
+                read_value::<i64>(&format!("{}/current_now", supply)),
+                read_value::<u64>(&format!("{}/voltage_now", supply)),
+            ) {
+                (Some(ua), Some(uv)) => Some(ua.abs() as f64 * uv as f64 / 1e12),
+                _ => None,
+            },
+        };
+        if let Some(watts) = power {
+            total = Some(total.unwrap_or(0.0) + watts);
+        }
+    }
+    total
+}
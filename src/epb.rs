@@ -0,0 +1,51 @@
+use serde_derive::Deserialize;
+
+/*
+    Intel Energy Performance Bias (energy_perf_bias), 0 = performance, 15 = power
+*/
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EnergyPerfBias {
+    Value(u8),
+    Name(String),
+}
+
+const EPB_NAMES: [(&str, u8); 5] = [
+    ("performance", 0),
+    ("balance-performance", 4),
+    ("normal", 6),
+    ("balance-power", 8),
+    ("power", 15),
+];
+
+impl EnergyPerfBias {
+    pub fn value(&self) -> Option<u8> {
+        match self {
+            EnergyPerfBias::Value(value) if *value <= 15 => Some(*value),
+            EnergyPerfBias::Value(_) => None,
+            EnergyPerfBias::Name(name) => EPB_NAMES
+                .iter()
+                .find(|(epb_name, _)| epb_name == name)
+                .map(|(_, value)| *value),
+        }
+    }
+}
+
+pub fn get_epb_name(value: u8) -> Option<&'static str> {
+    EPB_NAMES
+        .iter()
+        .find(|(_, epb_value)| *epb_value == value)
+        .map(|(name, _)| *name)
+}
+
+pub fn get_epb_path(cpu: i32) -> String {
+    format!("/sys/devices/system/cpu/cpu{}/power/energy_perf_bias", cpu)
+}
+
+pub fn get_epb(cpu: i32) -> Option<u8> {
+    match std::fs::read_to_string(get_epb_path(cpu)) {
+        Ok(value) => value.trim().parse::<u8>().ok(),
+        Err(_) => None,
+    }
+}
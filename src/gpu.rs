@@ -0,0 +1,233 @@
+use crate::snapshot::Snapshot;
+use crate::sysfs::{list_dir, read_value};
+use crossterm::style::Stylize;
+
+/*
+    Frequency caps of Intel integrated GPUs (i915/xe), either per card
+    (gt_*_freq_mhz) or per GT (gt/gtN/rps_*_freq_mhz)
+*/
+
+pub const DRM_PATH: &str = "/sys/class/drm";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpuFreqFiles {
+    pub name: String,
+    pub min: String,
+    pub max: String,
+    pub boost: String,
+    pub rp0: String,
+    pub rpn: String,
+}
+
+fn get_freq_files(name: String, dir: &str, prefix: &str) -> Option<GpuFreqFiles> {
+    let files = GpuFreqFiles {
+        name,
+        min: format!("{}/{}_min_freq_mhz", dir, prefix),
+        max: format!("{}/{}_max_freq_mhz", dir, prefix),
+        boost: format!("{}/{}_boost_freq_mhz", dir, prefix),
+        rp0: format!("{}/{}_RP0_freq_mhz", dir, prefix),
+        rpn: format!("{}/{}_RPn_freq_mhz", dir, prefix),
+    };
+    if std::fs::metadata(&files.min).is_ok() && std::fs::metadata(&files.max).is_ok() {
+        Some(files)
+    } else {
+        None
+    }
+}
+
+/*
+    The per-GT files are preferred, the card files only cover the first GT
+    on multi-tile GPUs. Connectors like card0-eDP-1 are skipped.
+*/
+pub fn get_gpus(drm_path: &str) -> Vec<GpuFreqFiles> {
+    let mut gpus: Vec<GpuFreqFiles> = Vec::new();
+    for card in list_dir(drm_path)
+        .into_iter()
+        .filter(|card| card.starts_with("card") && !card.contains('-'))
+    {
+        let card_path = format!("{}/{}", drm_path, card);
+        let gt_path = format!("{}/gt", card_path);
+        let gts: Vec<GpuFreqFiles> = list_dir(&gt_path)
+            .into_iter()
+            .filter(|gt| gt.starts_with("gt"))
+            .filter_map(|gt| {
+                get_freq_files(
+                    format!("{}/{}", card, gt),
+                    &format!("{}/{}", gt_path, gt),
+                    "rps",
+                )
+            })
+            .collect();
+        if gts.is_empty() {
+            gpus.extend(get_freq_files(card, &card_path, "gt"));
+        } else {
+            gpus.extend(gts);
+        }
+    }
+    gpus
+}
+
+/*
+    Returns the hardware range RPn-RP0 in MHz
+*/
+pub fn get_gpu_freq_range(gpu: &GpuFreqFiles) -> Option<(u32, u32)> {
+    Some((read_value(&gpu.rpn)?, read_value(&gpu.rp0)?))
+}
+
+pub fn get_gpu_freqs(gpu: &GpuFreqFiles) -> Option<(u32, u32)> {
+    Some((read_value(&gpu.min)?, read_value(&gpu.max)?))
+}
+
+pub fn save_gpu_freqs(gpus: &[GpuFreqFiles], snapshot: &mut Snapshot) {
+    for gpu in gpus.iter() {
+        snapshot.save(&gpu.min);
+        snapshot.save(&gpu.max);
+        if std::fs::metadata(&gpu.boost).is_ok() {
+            snapshot.save(&gpu.boost);
+        }
+    }
+}
+
+fn write_freq(path: &str, value: Option<u32>, snapshot: &Snapshot) {
+    let value = value.map(|value| value.to_string());
+    match snapshot.write_or_restore(path, value.as_deref()) {
+        Ok(_) => (),
+        Err(x) => eprintln!("[{}] Warning: Can't write '{}': {}", "!".yellow(), path, x),
+    }
+}
+
+/*
+    The driver rejects a maximum below the current minimum and vice versa,
+    so the order of the writes depends on the direction of the change.
+    Missing values are taken from the startup snapshot.
+*/
+pub fn set_gpu_freqs(
+    gpu: &GpuFreqFiles,
+    min: Option<u32>,
+    max: Option<u32>,
+    boost: Option<u32>,
+    snapshot: &Snapshot,
+) {
+    let (current_min, _) = match get_gpu_freqs(gpu) {
+        Some(current) => current,
+        None => return,
+    };
+    let new_max = max.or_else(|| snapshot.original(&gpu.max)?.parse::<u32>().ok());
+    if matches!(new_max, Some(max) if max < current_min) {
+        write_freq(&gpu.min, min, snapshot);
+        write_freq(&gpu.max, max, snapshot);
+    } else {
+        write_freq(&gpu.max, max, snapshot);
+        write_freq(&gpu.min, min, snapshot);
+    }
+    if std::fs::metadata(&gpu.boost).is_ok() {
+        write_freq(&gpu.boost, boost, snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(dir: &std::path::Path, file: &str, content: &str) {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn read(path: &str) -> String {
+        std::fs::read_to_string(path).unwrap().trim().to_string()
+    }
+
+    /*
+        card0 like i915 with the card files, RPn 300MHz and RP0 1300MHz
+    */
+    fn create_i915(drm: &std::path::Path) {
+        for (file, value) in [
+            ("min", "300"),
+            ("max", "1300"),
+            ("boost", "1300"),
+            ("RPn", "300"),
+            ("RP0", "1300"),
+        ]
+        .iter()
+        {
+            create(drm, &format!("card0/gt_{}_freq_mhz", file), value);
+        }
+        create(drm, "card0-eDP-1/status", "connected");
+    }
+
+    #[test]
+    fn finds_card_files() {
+        let drm = tempfile::tempdir().unwrap();
+        create_i915(drm.path());
+        let drm_path = drm.path().to_str().unwrap();
+        let gpus = get_gpus(drm_path);
+        assert_eq!(gpus.len(), 1);
+        assert_eq!(gpus[0].name, "card0");
+        assert_eq!(gpus[0].max, format!("{}/card0/gt_max_freq_mhz", drm_path));
+        assert_eq!(get_gpu_freqs(&gpus[0]), Some((300, 1300)));
+        assert_eq!(get_gpu_freq_range(&gpus[0]), Some((300, 1300)));
+    }
+
+    #[test]
+    fn prefers_gt_files() {
+        let drm = tempfile::tempdir().unwrap();
+        create_i915(drm.path());
+        for gt in ["gt0", "gt1"].iter() {
+            create(
+                drm.path(),
+                &format!("card0/gt/{}/rps_min_freq_mhz", gt),
+                "300",
+            );
+            create(
+                drm.path(),
+                &format!("card0/gt/{}/rps_max_freq_mhz", gt),
+                "1300",
+            );
+        }
+        let names: Vec<String> = get_gpus(drm.path().to_str().unwrap())
+            .into_iter()
+            .map(|gpu| gpu.name)
+            .collect();
+        assert_eq!(names, vec!["card0/gt0", "card0/gt1"]);
+    }
+
+    #[test]
+    fn skips_cards_without_freq_files() {
+        let drm = tempfile::tempdir().unwrap();
+        create(drm.path(), "card1/device/vendor", "0x1002");
+        assert!(get_gpus(drm.path().to_str().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn lowers_max_below_current_min() {
+        let drm = tempfile::tempdir().unwrap();
+        create_i915(drm.path());
+        create(drm.path(), "card0/gt_min_freq_mhz", "800");
+        let gpu = &get_gpus(drm.path().to_str().unwrap())[0];
+        let mut snapshot = Snapshot::default();
+        save_gpu_freqs(std::slice::from_ref(gpu), &mut snapshot);
+        set_gpu_freqs(gpu, Some(300), Some(500), Some(500), &snapshot);
+        assert_eq!(get_gpu_freqs(gpu), Some((300, 500)));
+        assert_eq!(read(&gpu.boost), "500");
+    }
+
+    #[test]
+    fn restores_missing_values_from_snapshot() {
+        let drm = tempfile::tempdir().unwrap();
+        create_i915(drm.path());
+        let gpu = &get_gpus(drm.path().to_str().unwrap())[0];
+        let mut snapshot = Snapshot::default();
+        save_gpu_freqs(std::slice::from_ref(gpu), &mut snapshot);
+        set_gpu_freqs(gpu, Some(400), Some(900), Some(900), &snapshot);
+        // e.g. back on AC without GPU settings
+        set_gpu_freqs(gpu, None, Some(1000), None, &snapshot);
+        assert_eq!(get_gpu_freqs(gpu), Some((300, 1000)));
+        assert_eq!(read(&gpu.boost), "1300");
+        set_gpu_freqs(gpu, Some(400), Some(900), Some(900), &snapshot);
+        snapshot.restore();
+        assert_eq!(get_gpu_freqs(gpu), Some((300, 1300)));
+        assert_eq!(read(&gpu.boost), "1300");
+    }
+}
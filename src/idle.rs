@@ -0,0 +1,173 @@
+use crossterm::style::Stylize;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use serde_derive::Deserialize;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zbus::blocking::{Connection, Proxy};
+
+/*
+    User idle time from logind's IdleHint/IdleSinceHint, or from the input
+    devices if there is no system bus
+*/
+
+const INPUT_PATH: &str = "/dev/input";
+// input devices are rescanned after this many seconds without events
+const RESCAN_INTERVAL: i32 = 60;
+
+static LOGIND: OnceLock<Option<Proxy<'static>>> = OnceLock::new();
+static INPUT_WATCH: OnceLock<bool> = OnceLock::new();
+static LAST_INPUT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct IdleConfig {
+    #[serde(default = "default_timeout")]
+    pub timeout: Option<u32>,
+    pub profile: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/*
+    Shared with the lid state, connected once
+*/
+pub fn get_logind_proxy() -> Option<&'static Proxy<'static>> {
+    LOGIND
+        .get_or_init(|| {
+            let connection = Connection::system().ok()?;
+            zbus::blocking::proxy::Builder::new(&connection)
+                .destination("org.freedesktop.login1")
+                .ok()?
+                .path("/org/freedesktop/login1")
+                .ok()?
+                .interface("org.freedesktop.login1.Manager")
+                .ok()?
+                .cache_properties(zbus::proxy::CacheProperties::No)
+                .build()
+                .ok()
+        })
+        .as_ref()
+}
+
+/*
+    IdleSinceHint is the wall clock time in microseconds of the last change
+    of IdleHint
+*/
+fn get_logind_idle_seconds() -> Option<u64> {
+    let proxy = get_logind_proxy()?;
+    let idle: bool = proxy.get_property("IdleHint").ok()?;
+    if !idle {
+        return Some(0);
+    }
+    let since: u64 = proxy.get_property("IdleSinceHint").ok()?;
+    Some(now().saturating_sub(since / 1_000_000))
+}
+
+fn open_input_devices() -> Vec<std::fs::File> {
+    let entries = match std::fs::read_dir(INPUT_PATH) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .filter_map(|entry| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(OFlag::O_NONBLOCK.bits())
+                .open(entry.path())
+                .ok()
+        })
+        .collect()
+}
+
+fn drain(fd: RawFd) {
+    let mut buf = [0u8; 1024];
+    while let Ok(len) = nix::unistd::read(fd, &mut buf) {
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+/*
+    Any event on any input device counts as activity, the events themselves
+    are discarded
+*/
+fn watch_input_devices() -> bool {
+    let devices = open_input_devices();
+    if devices.is_empty() {
+        return false;
+    }
+    LAST_INPUT.store(now(), Ordering::SeqCst);
+    std::thread::spawn(move || {
+        let mut devices = devices;
+        loop {
+            let mut fds: Vec<PollFd> = devices
+                .iter()
+                .map(|device| PollFd::new(device.as_raw_fd(), PollFlags::POLLIN))
+                .collect();
+            match poll(&mut fds, RESCAN_INTERVAL * 1000) {
+                Ok(0) => {
+                    devices = open_input_devices();
+                    continue;
+                }
+                Ok(_) => (),
+                Err(_) => continue,
+            }
+            let revents: Vec<PollFlags> = fds
+                .iter()
+                .map(|fd| fd.revents().unwrap_or_else(PollFlags::empty))
+                .collect();
+            // unplugged devices report errors until they are closed
+            if revents.iter().any(|revents| {
+                revents.intersects(PollFlags::POLLERR | PollFlags::POLLHUP | PollFlags::POLLNVAL)
+            }) {
+                devices = open_input_devices();
+                continue;
+            }
+            for (device, revents) in devices.iter().zip(revents.iter()) {
+                if revents.contains(PollFlags::POLLIN) {
+                    drain(device.as_raw_fd());
+                }
+            }
+            LAST_INPUT.store(now(), Ordering::SeqCst);
+        }
+    });
+    true
+}
+
+fn get_input_idle_seconds() -> Option<u64> {
+    let watching = *INPUT_WATCH.get_or_init(|| {
+        let watching = watch_input_devices();
+        if !watching {
+            // e.g. yablo.service hides /dev/input with PrivateDevices=true
+            eprintln!(
+                "[{}] Warning: Can't get the idle time from logind or {}, the user never counts as idle",
+                "!".yellow(),
+                INPUT_PATH
+            );
+        }
+        watching
+    });
+    if !watching {
+        return None;
+    }
+    Some(now().saturating_sub(LAST_INPUT.load(Ordering::SeqCst)))
+}
+
+pub fn get_idle_seconds() -> Option<u64> {
+    get_logind_idle_seconds().or_else(get_input_idle_seconds)
+}
+
+fn default_timeout() -> Option<u32> {
+    Some(10)
+}
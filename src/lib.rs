@@ -1,15 +1,61 @@
+pub mod backlight;
+pub mod cgroup;
+pub mod devices;
+pub mod dock;
+pub mod driver;
+pub mod energy;
+pub mod epb;
+pub mod gpu;
+pub mod idle;
+pub mod process;
+pub mod profile;
+pub mod rapl;
+pub mod runtime;
+pub mod schedule;
+pub mod sleep;
+pub mod snapshot;
+pub mod sysfs;
+#[cfg(test)]
+mod testbus;
+pub mod topology;
+pub mod tunables;
+pub mod uevent;
+pub mod wifi;
+
+use backlight::BacklightState;
+use cgroup::{CgroupLoad, CgroupMeter, CgroupThrottle, LoadConfig};
+use devices::{DeviceDenyList, DevicePowerConfig};
+use driver::{DriverInfo, TurboInterface};
+use energy::{EnergyMeter, EnergyState};
+use epb::EnergyPerfBias;
+use idle::IdleConfig;
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::unistd::Uid;
+use profile::{BatteryTier, DockConfig, PolicyInputs, Profile, ProfileOverride, Rule};
+use runtime::{RuntimeConfig, RuntimeState, RuntimeTarget};
+use schedule::Schedule;
 use serde_derive::Deserialize;
+use sleep::{SleepEvent, SleepWatcher, SuspendConfig};
+use snapshot::Snapshot;
 use systemstat::{Platform, System};
+use topology::CoreType;
+use tunables::TunablesConfig;
+use uevent::UeventSocket;
 
 use crossterm::event::{poll, read, Event, KeyCode, KeyEvent};
 use crossterm::style::Stylize;
 use crossterm::ExecutableCommand;
 use rev_lines::RevLines;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 const TIME_INCREMENT_PER_RUN: u32 = 4;
 
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static CHECKED_PROFILE: Mutex<Option<String>> = Mutex::new(None);
+
 /*
     Config related functions and structs
 */
@@ -18,10 +64,44 @@ const TIME_INCREMENT_PER_RUN: u32 = 4;
 pub struct Config {
     pub plugged_in: Option<PowerConfigAC>,
     pub on_battery: Option<PowerConfigBat>,
+    pub devices: Option<DeviceDenyList>,
+    pub tunables: Option<TunablesConfig>,
+    pub suspend: Option<SuspendConfig>,
+    #[serde(default)]
+    pub profiles: std::collections::BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    pub dock: Option<DockConfig>,
+    pub idle: Option<IdleConfig>,
+    #[serde(default)]
+    pub schedule: Vec<Schedule>,
+    pub load: Option<LoadConfig>,
+    pub runtime: Option<RuntimeConfig>,
+}
+
+/*
+    State kept between the runs of optimize_powerstate
+*/
+#[derive(Debug, Clone, Default)]
+pub struct DaemonState {
+    pub parked_cpus: Vec<i32>,
+    pub last_ac_power: Option<bool>,
+    pub backlight: BacklightState,
+    // set_at of a manual profile ended by a power source change
+    pub expired_override: Option<u64>,
+    pub runtime: RuntimeState,
+    // runs before the load may switch SMT again
+    pub smt_hold: u32,
+    // energy stats couldn't be shared, reported once
+    pub stats_failed: bool,
+    // between a suspend and the resume, no runs
+    pub suspended: bool,
+    pub resumed: bool,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct PowerConfigAC {
+    pub profile: Option<String>,
     pub governor: Option<String>,
     pub turbo: Option<bool>,
     #[serde(default = "default_second_stage_governor_plugged_in")]
@@ -32,10 +112,32 @@ pub struct PowerConfigAC {
     pub loadperc_threshold: Option<f32>,
     #[serde(default = "default_loadavg_threshold_plugged_in")]
     pub loadavg_threshold: Option<f32>,
+    pub pl1: Option<f32>,
+    pub pl2: Option<f32>,
+    pub second_stage_pl1: Option<f32>,
+    pub second_stage_pl2: Option<f32>,
+    pub performance_cores: Option<CoreClassConfig>,
+    pub efficiency_cores: Option<CoreClassConfig>,
+    pub amd_pstate_mode: Option<String>,
+    pub min_perf_pct: Option<u8>,
+    pub max_perf_pct: Option<u8>,
+    pub second_stage_min_perf_pct: Option<u8>,
+    pub second_stage_max_perf_pct: Option<u8>,
+    pub epb: Option<EnergyPerfBias>,
+    pub epp: Option<String>,
+    pub devices: Option<DevicePowerConfig>,
+    pub gpu_min_freq: Option<u32>,
+    pub gpu_max_freq: Option<u32>,
+    pub gpu_boost_freq: Option<u32>,
+    pub second_stage_gpu_min_freq: Option<u32>,
+    pub second_stage_gpu_max_freq: Option<u32>,
+    pub second_stage_gpu_boost_freq: Option<u32>,
+    pub wifi_power_save: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct PowerConfigBat {
+    pub profile: Option<String>,
     pub governor: Option<String>,
     pub turbo: Option<bool>,
     #[serde(default = "default_second_stage_governor_on_battery")]
@@ -50,6 +152,43 @@ pub struct PowerConfigBat {
     pub loadperc_threshold: Option<f32>,
     #[serde(default = "default_loadavg_threshold_on_battery")]
     pub loadavg_threshold: Option<f32>,
+    pub pl1: Option<f32>,
+    pub pl2: Option<f32>,
+    pub second_stage_pl1: Option<f32>,
+    pub second_stage_pl2: Option<f32>,
+    pub performance_cores: Option<CoreClassConfig>,
+    pub efficiency_cores: Option<CoreClassConfig>,
+    pub amd_pstate_mode: Option<String>,
+    pub min_perf_pct: Option<u8>,
+    pub max_perf_pct: Option<u8>,
+    pub second_stage_min_perf_pct: Option<u8>,
+    pub second_stage_max_perf_pct: Option<u8>,
+    pub epb: Option<EnergyPerfBias>,
+    pub epp: Option<String>,
+    pub devices: Option<DevicePowerConfig>,
+    pub gpu_min_freq: Option<u32>,
+    pub gpu_max_freq: Option<u32>,
+    pub gpu_boost_freq: Option<u32>,
+    pub second_stage_gpu_min_freq: Option<u32>,
+    pub second_stage_gpu_max_freq: Option<u32>,
+    pub second_stage_gpu_boost_freq: Option<u32>,
+    pub wifi_power_save: Option<bool>,
+    pub disable_smt_below: Option<u8>,
+    pub disable_smt_on_low_load: Option<bool>,
+    pub disable_smt_below_load: Option<f32>,
+    #[serde(default = "default_parked_cores")]
+    pub parked_cores: Option<u32>,
+    pub backlight: Option<u8>,
+    pub low_battery_backlight: Option<u8>,
+    pub throttle: Option<Vec<CgroupThrottle>>,
+    pub tiers: Option<Vec<BatteryTier>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CoreClassConfig {
+    pub governor: Option<String>,
+    pub epp: Option<String>,
+    pub max_freq: Option<u32>,
 }
 
 pub fn check_config_existence() {
@@ -89,181 +228,1301 @@ turbo = true
 }
 
 pub fn check_config_errors(config: &Config) {
+    check_rules(config);
+
     let avail_govs = get_available_governors();
 
     if avail_govs.is_empty() {
         eprintln!("[{}] Error: No govenors found. Exit.", "!".red());
-        std::process::exit(1)
+        exit_config_error()
     }
 
-    if !avail_govs.iter().any(|i| {
-        &i.as_str()
-            == config
-                .plugged_in
-                .as_ref()
-                .unwrap()
-                .governor
-                .as_ref()
-                .unwrap()
-    }) || !avail_govs.iter().any(|i| {
-        &i.as_str()
-            == config
-                .on_battery
-                .as_ref()
-                .unwrap()
-                .governor
-                .as_ref()
-                .unwrap()
-    }) || !avail_govs.iter().any(|i| {
-        &i.as_str()
-            == config
-                .plugged_in
-                .as_ref()
-                .unwrap()
-                .second_stage_governor
-                .as_ref()
-                .unwrap()
-    }) || !avail_govs.iter().any(|i| {
-        &i.as_str()
-            == config
-                .on_battery
-                .as_ref()
-                .unwrap()
-                .second_stage_governor
-                .as_ref()
-                .unwrap()
-    }) {
-        eprintln!("[{}] Error: At least one governor specified in config file isn't available!\n     'cat /sys/devices/system/cpu/cpu0/cpufreq/scaling_available_governors' to see available governors", "!".red());
-        std::process::exit(1)
+    let ac = config.plugged_in.as_ref().unwrap();
+    let bat = config.on_battery.as_ref().unwrap();
+    let current_mode = std::fs::read_to_string(driver::AMD_PSTATE_STATUS).unwrap_or_default();
+    for (mode, governors) in [
+        (
+            ac.amd_pstate_mode.as_ref(),
+            [ac.governor.as_ref(), ac.second_stage_governor.as_ref()],
+        ),
+        (
+            bat.amd_pstate_mode.as_ref(),
+            [bat.governor.as_ref(), bat.second_stage_governor.as_ref()],
+        ),
+    ]
+    .iter()
+    {
+        if let Some(mode) = mode {
+            if !driver::AMD_PSTATE_MODES.contains(&mode.as_str()) {
+                eprintln!(
+                    "[{}] Error: Unknown amd_pstate_mode '{}'! Use one of {:?}",
+                    "!".red(),
+                    mode,
+                    driver::AMD_PSTATE_MODES
+                );
+                exit_config_error()
+            }
+            if std::fs::metadata(driver::AMD_PSTATE_STATUS).is_err() {
+                eprintln!(
+                    "[{}] Error: amd_pstate_mode specified in config file but amd-pstate isn't available!",
+                    "!".red()
+                );
+                exit_config_error()
+            }
+        }
+        // passive and guided mode offer the generic governors, which can't be
+        // listed while another mode is active
+        let mode_govs = match mode.map(|mode| mode.as_str()) {
+            Some(mode) if mode == current_mode.trim() => avail_govs.clone(),
+            Some("active") => vec![String::from("performance"), String::from("powersave")],
+            Some(_) => continue,
+            None => avail_govs.clone(),
+        };
+        if governors.iter().any(|governor| {
+            !mode_govs
+                .iter()
+                .any(|i| i.trim() == governor.unwrap().as_str())
+        }) {
+            eprintln!("[{}] Error: At least one governor specified in config file isn't available!\n     'cat /sys/devices/system/cpu/cpu0/cpufreq/scaling_available_governors' to see available governors", "!".red());
+            exit_config_error()
+        }
     }
-}
 
-pub fn get_config() -> Config {
-    let decoded_config: Config = match toml::from_str(
-        &std::fs::read_to_string("/etc/yablo/config.toml")
-            .expect("Something went wrong reading config file"),
-    ) {
-        Ok(c) => c,
-        Err(x) => {
+    let power_limits = get_configured_power_limits(config);
+    if !power_limits.is_empty() && !rapl::check_rapl_availability() {
+        eprintln!(
+            "[{}] Error: Power limits specified in config file but no intel-rapl powercap zone found!",
+            "!".red()
+        );
+        exit_config_error()
+    }
+    for (constraint, watts) in power_limits.iter() {
+        if let Err(x) = rapl::check_power_limit(constraint, *watts) {
             eprintln!("[{}] Error: {}", "!".red(), x);
-            std::process::exit(1)
+            exit_config_error()
         }
-    };
-    decoded_config
-}
+    }
+    // the long term limit can't be above the short term one
+    for (section, pl1, pl2) in [
+        ("plugged_in", ac.pl1, ac.pl2),
+        ("plugged_in", ac.second_stage_pl1, ac.second_stage_pl2),
+        ("on_battery", bat.pl1, bat.pl2),
+        ("on_battery", bat.second_stage_pl1, bat.second_stage_pl2),
+    ]
+    .iter()
+    {
+        if let (Some(pl1), Some(pl2)) = (pl1, pl2) {
+            if pl1 > pl2 {
+                eprintln!(
+                    "[{}] Error: PL1 of {}W is above PL2 of {}W in [{}]!",
+                    "!".red(),
+                    pl1,
+                    pl2,
+                    section
+                );
+                exit_config_error()
+            }
+        }
+    }
 
-/*
-    System info collection
-*/
-pub struct SystemInfo {
-    pub temperature: f32,
-    pub ac_power: bool,
-    pub loadavg: f32,
-    pub loadperc: f32,
-    pub mem_usage: (u64, u64),
-    pub turbo_invert: bool,
-    pub turbo_avail: bool,
-    pub cpu_freqs: Vec<i32>,
-    pub battery_capacity: u8,
-}
+    if uses_smt_control(config) && get_smt().is_none() {
+        eprintln!(
+            "[{}] Error: SMT options specified in config file but SMT can't be controlled on this system!",
+            "!".red()
+        );
+        exit_config_error()
+    }
+    if bat.disable_smt_on_low_load.unwrap_or(false)
+        && get_smt_off_load(bat) >= bat.loadperc_threshold.unwrap()
+    {
+        eprintln!(
+            "[{}] Error: disable_smt_below_load has to be below loadperc_threshold!",
+            "!".red()
+        );
+        exit_config_error()
+    }
 
-pub fn get_sys_info(sys: &System, turbo_avail: bool, invert: bool, num_cpus: i32) -> SystemInfo {
-    SystemInfo {
-        loadavg: match sys.load_average() {
-            Ok(loadavg) => loadavg.one,
-            Err(x) => {
-                eprintln!("[{}] Error: {}", "!".red(), x);
-                std::process::exit(1)
+    check_core_class_config(config, &avail_govs);
+
+    if let Some(governor) = config
+        .suspend
+        .as_ref()
+        .and_then(|suspend| suspend.governor.as_ref())
+    {
+        if !avail_govs.iter().any(|i| i.trim() == governor) {
+            eprintln!(
+                "[{}] Error: Suspend governor '{}' isn't available!",
+                "!".red(),
+                governor
+            );
+            exit_config_error()
+        }
+    }
+
+    for epb in [
+        config.plugged_in.as_ref().unwrap().epb.as_ref(),
+        config.on_battery.as_ref().unwrap().epb.as_ref(),
+    ]
+    .iter()
+    .flatten()
+    {
+        if epb.value().is_none() {
+            eprintln!(
+                "[{}] Error: Invalid epb {:?}! Use 0-15 or one of 'performance', 'balance-performance', 'normal', 'balance-power', 'power'",
+                "!".red(),
+                epb
+            );
+            exit_config_error()
+        }
+        if epb::get_epb(0).is_none() {
+            eprintln!(
+                "[{}] Error: epb specified in config file but energy_perf_bias isn't available!",
+                "!".red()
+            );
+            exit_config_error()
+        }
+    }
+
+    for epp in [
+        config.plugged_in.as_ref().unwrap().epp.as_ref(),
+        config.on_battery.as_ref().unwrap().epp.as_ref(),
+    ]
+    .iter()
+    .flatten()
+    {
+        let available = std::fs::read_to_string(EPP_AVAILABLE).unwrap_or_default();
+        if !available.split_whitespace().any(|i| i == *epp) {
+            eprintln!(
+                "[{}] Error: EPP '{}' isn't available!\n     'cat {}' to see available preferences",
+                "!".red(),
+                epp,
+                EPP_AVAILABLE
+            );
+            exit_config_error()
+        }
+    }
+
+    let ac = config.plugged_in.as_ref().unwrap();
+    let bat = config.on_battery.as_ref().unwrap();
+    for (min, max) in [
+        (ac.min_perf_pct, ac.max_perf_pct),
+        (ac.second_stage_min_perf_pct, ac.second_stage_max_perf_pct),
+        (bat.min_perf_pct, bat.max_perf_pct),
+        (bat.second_stage_min_perf_pct, bat.second_stage_max_perf_pct),
+    ]
+    .iter()
+    {
+        if min.is_none() && max.is_none() {
+            continue;
+        }
+        if get_perf_pct().is_none() {
+            eprintln!(
+                "[{}] Error: min_perf_pct/max_perf_pct specified in config file but intel_pstate isn't active!",
+                "!".red()
+            );
+            exit_config_error()
+        }
+        if min.unwrap_or(0) > 100
+            || max.unwrap_or(100) > 100
+            || min.unwrap_or(0) > max.unwrap_or(100)
+        {
+            eprintln!(
+                "[{}] Error: min_perf_pct and max_perf_pct have to be within 0-100% and min_perf_pct <= max_perf_pct!",
+                "!".red()
+            );
+            exit_config_error()
+        }
+    }
+
+    for devices in [
+        config.plugged_in.as_ref().unwrap().devices.as_ref(),
+        config.on_battery.as_ref().unwrap().devices.as_ref(),
+    ]
+    .iter()
+    .flatten()
+    {
+        if let Some(policy) = devices.pcie_aspm.as_ref() {
+            match devices::get_aspm_policies() {
+                Some((policies, _)) if policies.contains(policy) => (),
+                _ => {
+                    eprintln!(
+                        "[{}] Error: PCIe ASPM policy '{}' isn't available!\n     'cat /sys/module/pcie_aspm/parameters/policy' to see available policies",
+                        "!".red(),
+                        policy
+                    );
+                    exit_config_error()
+                }
             }
-        },
-        temperature: match sys.cpu_temp() {
-            Ok(temp) => temp,
-            Err(x) => {
-                eprintln!("[{}] Error: {}", "!".red(), x);
-                std::process::exit(1)
+        }
+        if let Some(policy) = devices.sata_alpm.as_ref() {
+            if !devices::ALPM_POLICIES.contains(&policy.as_str()) {
+                eprintln!(
+                    "[{}] Error: Unknown SATA link power management policy '{}'! Use one of {:?}",
+                    "!".red(),
+                    policy,
+                    devices::ALPM_POLICIES
+                );
+                exit_config_error()
             }
-        },
-        ac_power: on_ac_power(),
-        loadperc: match sys.cpu_load_aggregate() {
-            Ok(cpu) => {
-                std::thread::sleep(std::time::Duration::from_millis(1000));
-                let cpu = cpu.done().unwrap();
-                cpu.user * 100.0
-                // cpu.system * 100.0
+            if !devices::check_alpm_availability() {
+                eprintln!(
+                    "[{}] Error: sata_alpm specified in config file but no SATA host found!",
+                    "!".red()
+                );
+                exit_config_error()
             }
+        }
+    }
+
+    let parked_cores = config.on_battery.as_ref().unwrap().parked_cores.unwrap();
+    if parked_cores as usize > get_parkable_cpus().len() {
+        eprintln!(
+            "[{}] Error: Can't park {} cores, only {} CPUs support hotplug!",
+            "!".red(),
+            parked_cores,
+            get_parkable_cpus().len()
+        );
+        exit_config_error()
+    }
+
+    check_gpu_freqs(config);
+
+    if uses_wifi_power_save(config) {
+        let interfaces = match wifi::get_wifi_interfaces() {
+            Ok(interfaces) => interfaces,
             Err(x) => {
-                eprintln!("[{}] Error: {}", "!".red(), x);
-                std::process::exit(1)
+                eprintln!(
+                    "[{}] Error: Can't list Wi-Fi interfaces over nl80211: {}. As a service this needs yablo.service.d/wifi-power-save.conf!",
+                    "!".red(),
+                    x
+                );
+                exit_config_error()
             }
-        },
-        turbo_avail: turbo_avail,
-        turbo_invert: invert,
-        cpu_freqs: get_cpu_freq(num_cpus),
-        mem_usage: match sys.memory() {
-            Ok(mem) => (mem.total.as_u64(), mem.free.as_u64()),
-            Err(x) => {
-                eprintln!("[{}] Error: {}", "!".red(), x);
-                std::process::exit(1)
+        };
+        match interfaces.first() {
+            Some(interface) => match wifi::get_power_save(interface) {
+                Ok(_) => (),
+                Err(x) => {
+                    eprintln!(
+                        "[{}] Error: Can't read power save state of '{}' over nl80211: {}",
+                        "!".red(),
+                        interface,
+                        x
+                    );
+                    exit_config_error()
+                }
+            },
+            None => {
+                eprintln!(
+                    "[{}] Error: wifi_power_save specified in config file but no Wi-Fi interface found!",
+                    "!".red()
+                );
+                exit_config_error()
             }
-        },
-        battery_capacity: get_battery_percentage(),
+        }
     }
-}
 
-fn get_battery_percentage() -> u8 {
-    let bat0_path = "/sys/class/power_supply/BAT0/capacity";
-    let bat1_path = "/sys/class/power_supply/BAT1/capacity";
-    let bat0_avail = match std::fs::metadata(bat0_path) {
-        Ok(_) => true,
-        Err(_) => false,
-    };
-    let bat1_avail = match std::fs::metadata(bat1_path) {
-        Ok(_) => true,
-        Err(_) => false,
-    };
-    let batteries = (bat0_avail, bat1_avail);
+    let bat = config.on_battery.as_ref().unwrap();
+    for (key, percent) in [
+        ("backlight", bat.backlight),
+        ("low_battery_backlight", bat.low_battery_backlight),
+    ] {
+        if let Some(percent) = percent {
+            if percent == 0 || percent > 100 {
+                eprintln!(
+                    "[{}] Error: {} has to be between 1 and 100!",
+                    "!".red(),
+                    key
+                );
+                exit_config_error()
+            }
+            if backlight::get_backlights().is_empty() {
+                eprintln!(
+                    "[{}] Error: {} specified in config file but no backlight found!",
+                    "!".red(),
+                    key
+                );
+                exit_config_error()
+            }
+        }
+    }
 
-    match batteries {
-        (true, true) => {
-            let bat0_capacity = match std::fs::read_to_string(bat0_path) {
-                Ok(capacity) => capacity.replace('\n', "").parse::<u8>().unwrap(),
-                Err(_) => {
-                    eprintln!("[{}] Error: Reading battery capacity for BAT0 (\'/sys/class/power_supply/BAT0/capacity\')", "!".red());
-                    std::process::exit(1);
-                }
-            };
-            let bat1_capacity = match std::fs::read_to_string(bat1_path) {
-                Ok(capacity) => capacity.replace('\n', "").parse::<u8>().unwrap(),
-                Err(_) => {
-                    eprintln!("[{}] Error: Reading battery capacity for BAT1 (\'/sys/class/power_supply/BAT1/capacity\')", "!".red());
-                    std::process::exit(1);
+    if let Some(tunables) = config.tunables.as_ref() {
+        match tunables::get_tunables(tunables) {
+            Ok(tunables) => {
+                for (path, _) in tunables.iter() {
+                    if std::fs::metadata(path).is_err() {
+                        eprintln!(
+                            "[{}] Error: Tunable '{}' specified in config file doesn't exist!",
+                            "!".red(),
+                            path
+                        );
+                        exit_config_error()
+                    }
                 }
-            };
-            return (bat0_capacity + bat1_capacity) / 2;
+            }
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                exit_config_error()
+            }
         }
-        (true, false) => {
-            match std::fs::read_to_string(bat0_path) {
-                Ok(capacity) => return capacity.replace('\n', "").parse::<u8>().unwrap(),
-                Err(_) => {
-                    eprintln!("[{}] Error: Reading battery capacity for BAT0 (\'/sys/class/power_supply/BAT0/capacity\')", "!".red());
-                    std::process::exit(1);
-                }
-            };
+    }
+
+    let throttles = get_configured_throttles(config);
+    if !throttles.is_empty() && !cgroup::is_cgroup_v2() {
+        eprintln!(
+            "[{}] Error: throttle specified in config file but cgroup v2 isn't mounted at {}!",
+            "!".red(),
+            cgroup::CGROUP_PATH
+        );
+        exit_config_error()
+    }
+    if throttles
+        .iter()
+        .any(|throttle| !throttle.get_properties().is_empty())
+        && !cgroup::has_systemd()
+    {
+        eprintln!(
+            "[{}] Error: cpu_max and cpu_weight are set through systemd, but the system bus isn't available!",
+            "!".red()
+        );
+        exit_config_error()
+    }
+    for throttle in throttles.iter() {
+        if !throttle.get_properties().is_empty() && !cgroup::is_cgroup_unit(&throttle.get_unit()) {
+            eprintln!(
+                "[{}] Error: cpu_max and cpu_weight of '{}' need a systemd unit, e.g. background.slice!",
+                "!".red(),
+                throttle.cgroup
+            );
+            exit_config_error()
         }
-        (false, true) => {
-            match std::fs::read_to_string(bat1_path) {
-                Ok(capacity) => return capacity.replace('\n', "").parse::<u8>().unwrap(),
-                Err(_) => {
-                    eprintln!("[{}] Error: Reading battery capacity for BAT1 (\'/sys/class/power_supply/BAT1/capacity\')", "!".red());
-                    std::process::exit(1);
-                }
-            };
+        if throttle.cpu_max == Some(0) {
+            eprintln!(
+                "[{}] Error: cpu_max of '{}' has to be above 0!",
+                "!".red(),
+                throttle.cgroup
+            );
+            exit_config_error()
         }
-        (false, false) => {
-            return 101;
+        if throttle
+            .cpu_weight
+            .is_some_and(|weight| !(1..=10000).contains(&weight))
+        {
+            eprintln!(
+                "[{}] Error: cpu_weight of '{}' has to be between 1 and 10000!",
+                "!".red(),
+                throttle.cgroup
+            );
+            exit_config_error()
+        }
+        if throttle
+            .uclamp_max
+            .is_some_and(|uclamp| !(0.0..=100.0).contains(&uclamp))
+        {
+            eprintln!(
+                "[{}] Error: uclamp_max of '{}' has to be between 0 and 100!",
+                "!".red(),
+                throttle.cgroup
+            );
+            exit_config_error()
         }
     }
-}
+
+    if let Some(load) = config.load.as_ref() {
+        if !cgroup::is_cgroup_v2() {
+            eprintln!(
+                "[{}] Error: [load] specified in config file but cgroup v2 isn't mounted at {}!",
+                "!".red(),
+                cgroup::CGROUP_PATH
+            );
+            exit_config_error()
+        }
+        for (path, weight) in load.weights.iter() {
+            if !(0.0..=1.0).contains(weight) {
+                eprintln!(
+                    "[{}] Error: Load weight of '{}' has to be between 0 and 1!",
+                    "!".red(),
+                    path
+                );
+                exit_config_error()
+            }
+        }
+    }
+
+    // every profile has to result in a valid config for both power sources,
+    // checked after the base config so its errors aren't blamed on a profile
+    for (name, ac_power, profile_config) in get_profile_configs(config) {
+        let source = if ac_power { "plugged in" } else { "on battery" };
+        *CHECKED_PROFILE.lock().unwrap() = Some(format!("'{}' {}", name, source));
+        check_config_errors(&profile_config);
+        *CHECKED_PROFILE.lock().unwrap() = None;
+    }
+}
+
+/*
+    A profile is checked as the config it results in, so the error message
+    alone doesn't tell which profile is wrong
+*/
+fn exit_config_error() -> ! {
+    if let Some(profile) = CHECKED_PROFILE.lock().unwrap().as_ref() {
+        eprintln!("     in profile {}", profile);
+    }
+    std::process::exit(1)
+}
+
+fn check_rules(config: &Config) {
+    for profile in [
+        config.plugged_in.as_ref().unwrap().profile.as_ref(),
+        config.on_battery.as_ref().unwrap().profile.as_ref(),
+    ]
+    .iter()
+    .flatten()
+    {
+        if !config.profiles.contains_key(*profile) {
+            eprintln!(
+                "[{}] Error: Default profile '{}' doesn't exist!",
+                "!".red(),
+                profile
+            );
+            exit_config_error()
+        }
+    }
+    for tier in config.on_battery.as_ref().unwrap().tiers.iter().flatten() {
+        if !config.profiles.contains_key(&tier.profile) {
+            eprintln!(
+                "[{}] Error: Battery tier uses unknown profile '{}'!",
+                "!".red(),
+                tier.profile
+            );
+            exit_config_error()
+        }
+        if !(1..=100).contains(&tier.below) {
+            eprintln!(
+                "[{}] Error: below of battery tier '{}' has to be between 1 and 100!",
+                "!".red(),
+                tier.profile
+            );
+            exit_config_error()
+        }
+    }
+    for schedule in config.schedule.iter() {
+        if !config.profiles.contains_key(&schedule.profile) {
+            eprintln!(
+                "[{}] Error: Schedule uses unknown profile '{}'!",
+                "!".red(),
+                schedule.profile
+            );
+            exit_config_error()
+        }
+        for time in [&schedule.start, &schedule.end] {
+            if schedule::parse_time(time).is_none() {
+                eprintln!(
+                    "[{}] Error: Invalid schedule time '{}'! Use HH:MM",
+                    "!".red(),
+                    time
+                );
+                exit_config_error()
+            }
+        }
+        for day in schedule.days.iter() {
+            if !schedule::WEEKDAYS
+                .iter()
+                .any(|weekday| weekday.eq_ignore_ascii_case(day))
+            {
+                eprintln!(
+                    "[{}] Error: Unknown weekday '{}'! Use one of {:?}",
+                    "!".red(),
+                    day,
+                    schedule::WEEKDAYS
+                );
+                exit_config_error()
+            }
+        }
+    }
+    if let Some(profile) = config.idle.as_ref().and_then(|idle| idle.profile.as_ref()) {
+        if !config.profiles.contains_key(profile) {
+            eprintln!(
+                "[{}] Error: Idle profile '{}' doesn't exist!",
+                "!".red(),
+                profile
+            );
+            exit_config_error()
+        }
+    }
+    if let Some(runtime) = config.runtime.as_ref() {
+        if runtime.levels.is_empty() {
+            eprintln!(
+                "[{}] Error: [runtime] specified in config file without levels!",
+                "!".red()
+            );
+            exit_config_error()
+        }
+        for level in runtime.levels.iter() {
+            if !config.profiles.contains_key(level) {
+                eprintln!(
+                    "[{}] Error: Runtime level '{}' doesn't exist!",
+                    "!".red(),
+                    level
+                );
+                exit_config_error()
+            }
+        }
+    }
+    for rule in config.rules.iter() {
+        if !config.profiles.contains_key(&rule.profile) {
+            eprintln!(
+                "[{}] Error: Rule uses unknown profile '{}'!",
+                "!".red(),
+                rule.profile
+            );
+            exit_config_error()
+        }
+        if let Some(lid) = rule.lid.as_ref() {
+            if !profile::LID_STATES.contains(&lid.as_str()) {
+                eprintln!(
+                    "[{}] Error: Unknown lid state '{}'! Use one of {:?}",
+                    "!".red(),
+                    lid,
+                    profile::LID_STATES
+                );
+                exit_config_error()
+            }
+        }
+    }
+}
+
+/*
+    All profiles applied to both power sources, without profiles and the
+    settings referring to them
+*/
+fn get_profile_configs(config: &Config) -> Vec<(String, bool, Config)> {
+    let mut base = config.clone();
+    base.profiles.clear();
+    base.rules.clear();
+    base.schedule.clear();
+    base.runtime = None;
+    base.plugged_in.as_mut().unwrap().profile = None;
+    base.on_battery.as_mut().unwrap().profile = None;
+    base.on_battery.as_mut().unwrap().tiers = None;
+    if let Some(idle) = base.idle.as_mut() {
+        idle.profile = None;
+    }
+    let mut configs = Vec::new();
+    for (name, profile) in &config.profiles {
+        for ac_power in [true, false].iter() {
+            let profile_config = profile::apply_profile(&base, profile, *ac_power);
+            configs.push((name.clone(), *ac_power, profile_config));
+        }
+    }
+    configs
+}
+
+fn get_dock_ids(config: &Config) -> Vec<String> {
+    match config.dock.as_ref() {
+        Some(dock) => dock.usb_ids.clone(),
+        None => Vec::new(),
+    }
+}
+
+fn get_policy_inputs(config: &Config, sys_info: &SystemInfo) -> PolicyInputs {
+    let dock_ids = get_dock_ids(config);
+    // without known dock devices an external display counts as docked
+    let docked = if dock_ids.is_empty() {
+        !sys_info.external_displays.is_empty()
+    } else if config.rules.iter().any(|rule| rule.docked.is_some()) {
+        dock::is_dock_connected(&dock_ids)
+    } else {
+        false
+    };
+    let processes = if config.rules.iter().any(|rule| rule.uses_processes()) {
+        process::get_processes(
+            config.rules.iter().any(|rule| rule.cmdline.is_some()),
+            config.rules.iter().any(|rule| rule.cgroup.is_some()),
+        )
+    } else {
+        Vec::new()
+    };
+    PolicyInputs {
+        ac_power: sys_info.ac_power,
+        lid_closed: sys_info.lid_closed,
+        external_display: !sys_info.external_displays.is_empty(),
+        docked,
+        processes,
+    }
+}
+
+fn get_battery_tier(config: &Config, ac_power: bool, capacity: u8) -> Option<&BatteryTier> {
+    if ac_power {
+        return None;
+    }
+    let tiers = config.on_battery.as_ref().unwrap().tiers.as_ref()?;
+    profile::select_tier(tiers, capacity)
+}
+
+/*
+    Below battery_threshold the low battery governor without turbo replaces
+    the load stages. Tiers bring their own governor and turbo for low
+    capacities, so the threshold doesn't override them
+*/
+fn is_low_battery(config: &Config, capacity: u8) -> bool {
+    let bat = config.on_battery.as_ref().unwrap();
+    bat.tiers.as_ref().is_none_or(|tiers| tiers.is_empty())
+        && capacity <= bat.battery_threshold.unwrap()
+}
+
+fn is_user_idle(config: &Config, sys_info: &SystemInfo) -> bool {
+    match (config.idle.as_ref(), sys_info.idle_seconds) {
+        (Some(idle), Some(seconds)) => seconds >= idle.timeout.unwrap() as u64 * 60,
+        _ => false,
+    }
+}
+
+/*
+    Returns the config with the idle profile or the profile of the first
+    matching rule applied
+*/
+fn get_effective_config(
+    config: &Config,
+    sys_info: &SystemInfo,
+    manual: Option<&ProfileOverride>,
+) -> (Config, Option<String>) {
+    let default_profile = if sys_info.ac_power {
+        config.plugged_in.as_ref().unwrap().profile.as_ref()
+    } else {
+        config.on_battery.as_ref().unwrap().profile.as_ref()
+    };
+    let config_default = match default_profile {
+        Some(profile) => {
+            profile::apply_profile(config, &config.profiles[profile], sys_info.ac_power)
+        }
+        None => config.clone(),
+    };
+    // the battery tier is part of the base settings on battery
+    let tier = get_battery_tier(config, sys_info.ac_power, sys_info.battery_capacity);
+    let config_default = match tier {
+        Some(tier) => {
+            profile::apply_profile(&config_default, &config.profiles[&tier.profile], false)
+        }
+        None => config_default,
+    };
+    // a manual profile replaces the automatic selection
+    if let Some(manual) = manual {
+        if let Some(profile) = config.profiles.get(&manual.profile) {
+            return (
+                profile::apply_profile(&config_default, profile, sys_info.ac_power),
+                Some(format!("{} (manual)", manual.profile)),
+            );
+        }
+    }
+    // the active schedule replaces the base settings, rules and idle apply on top
+    let base = match get_active_schedule(config, sys_info.ac_power) {
+        Some(schedule) => profile::apply_profile(
+            &config_default,
+            &config.profiles[&schedule.profile],
+            sys_info.ac_power,
+        ),
+        None => config_default,
+    };
+    if is_user_idle(config, sys_info) {
+        if let Some(profile) = config.idle.as_ref().unwrap().profile.as_ref() {
+            return (
+                profile::apply_profile(&base, &config.profiles[profile], sys_info.ac_power),
+                Some(format!("{} (user idle)", profile)),
+            );
+        }
+    }
+    if config.rules.is_empty() {
+        return (base, None);
+    }
+    let inputs = get_policy_inputs(config, sys_info);
+    match profile::select_profile(&config.rules, &inputs) {
+        Some(rule) => {
+            let reason = rule.reason(&inputs);
+            let label = if reason.is_empty() {
+                rule.profile.clone()
+            } else {
+                format!("{} ({})", rule.profile, reason)
+            };
+            (
+                profile::apply_profile(&base, &config.profiles[&rule.profile], sys_info.ac_power),
+                Some(label),
+            )
+        }
+        None => (base, None),
+    }
+}
+
+fn get_active_schedule(config: &Config, ac_power: bool) -> Option<&Schedule> {
+    if config.schedule.is_empty() {
+        return None;
+    }
+    schedule::get_active_schedule(&config.schedule, &schedule::get_local_time(), ac_power)
+}
+
+fn get_runtime_target(config: &Config, sys_info: &SystemInfo) -> Option<RuntimeTarget> {
+    config.runtime.as_ref()?;
+    if sys_info.ac_power {
+        return None;
+    }
+    runtime::read_target().filter(|target| !target.is_expired(profile::get_unix_time()))
+}
+
+/*
+    With a runtime target on battery the levels reached so far are applied
+    on top of the effective config. The feedback loop replaces the fixed
+    battery_threshold cut-off
+*/
+fn apply_runtime_target(
+    config: &Config,
+    effective_config: Config,
+    sys_info: &SystemInfo,
+    state: &mut RuntimeState,
+) -> Config {
+    let target = match get_runtime_target(config, sys_info) {
+        Some(target) => target,
+        None => {
+            state.reset();
+            return effective_config;
+        }
+    };
+    let runtime = config.runtime.as_ref().unwrap();
+    let needed = target.until.saturating_sub(profile::get_unix_time());
+    match (runtime::get_battery_energy(), energy::get_battery_power()) {
+        (Some(energy), Some(power)) => {
+            if let Some(projection) = state.update(
+                energy,
+                power,
+                needed,
+                runtime.levels.len(),
+                runtime.slack.unwrap(),
+            ) {
+                println!(
+                    "[{}] Runtime target: {} left, projected {} at {:.1}W",
+                    "+".dark_green(),
+                    runtime::format_duration(projection.needed),
+                    runtime::format_duration(projection.projected),
+                    projection.power
+                );
+            }
+        }
+        _ => eprintln!(
+            "[{}] Warning: Can't read battery energy or power for the runtime target",
+            "!".yellow()
+        ),
+    }
+    let mut effective_config = effective_config;
+    for level in runtime.levels.iter().take(state.level) {
+        effective_config =
+            profile::apply_profile(&effective_config, &config.profiles[level], false);
+    }
+    if state.level > 0 {
+        println!(
+            "[{}] Runtime level {}/{} ('{}')",
+            "+".dark_green(),
+            state.level,
+            runtime.levels.len(),
+            runtime.levels[state.level - 1]
+        );
+    }
+    effective_config
+        .on_battery
+        .as_mut()
+        .unwrap()
+        .battery_threshold = Some(0);
+    effective_config
+}
+
+/*
+    The schedule is part of the config, so it is looked up after get_sys_info
+*/
+pub fn update_schedule(config: &Config, sys_info: &mut SystemInfo) {
+    sys_info.schedule = get_active_schedule(config, sys_info.ac_power)
+        .map(|schedule| format!("{} ({}-{})", schedule.profile, schedule.start, schedule.end));
+}
+
+/*
+    The lid, displays and USB devices are only probed for rules that match
+    on them
+*/
+pub fn update_dock(config: &Config, sys_info: &mut SystemInfo) {
+    if config.rules.iter().any(|rule| rule.lid.is_some()) {
+        sys_info.lid_closed = dock::get_lid_closed();
+    }
+    let docked_by_display =
+        get_dock_ids(config).is_empty() && config.rules.iter().any(|rule| rule.docked.is_some());
+    if docked_by_display
+        || config
+            .rules
+            .iter()
+            .any(|rule| rule.external_display.is_some())
+    {
+        sys_info.external_displays = dock::get_external_displays();
+    }
+}
+
+/*
+    Only the daemon asks for the idle time, and only with an [idle] table,
+    as the first call may start watching /dev/input
+*/
+pub fn update_idle(config: &Config, sys_info: &mut SystemInfo) {
+    if config.idle.is_some() {
+        sys_info.idle_seconds = idle::get_idle_seconds();
+    }
+}
+
+/*
+    Leaves the load of excluded and down-weighted cgroups since the last
+    sample out of loadavg and loadperc. loadavg is lowered by the CPUs these
+    cgroups kept busy, loadperc by their share of the user CPU time
+*/
+pub fn update_load(config: &Config, meter: &mut CgroupMeter, sys_info: &mut SystemInfo) {
+    let weights = match config.load.as_ref() {
+        Some(load) => load.get_weights(),
+        None => return,
+    };
+    let paths: Vec<String> = weights.keys().cloned().collect();
+    let usage = match meter.sample(&paths, get_online_cpus().len()) {
+        Some(usage) => usage,
+        None => return,
+    };
+    let discount = meter.smooth(cgroup::get_discount(&weights, &usage));
+    sys_info.loadavg = (sys_info.loadavg - discount.busy).max(0.0);
+    sys_info.loadperc = (sys_info.loadperc - discount.user).max(0.0);
+    sys_info.ignored_load = Some(discount);
+}
+
+fn get_configured_throttles(config: &Config) -> Vec<CgroupThrottle> {
+    config
+        .on_battery
+        .as_ref()
+        .unwrap()
+        .throttle
+        .clone()
+        .unwrap_or_default()
+}
+
+fn get_configured_tunables(config: &Config) -> Vec<(String, String)> {
+    match config.tunables.as_ref() {
+        Some(tunables) => tunables::get_tunables(tunables).unwrap_or_default(),
+        None => Vec::new(),
+    }
+}
+
+fn get_configured_gpu_freqs(config: &Config) -> Vec<(Option<u32>, Option<u32>, Option<u32>)> {
+    let ac = config.plugged_in.as_ref().unwrap();
+    let bat = config.on_battery.as_ref().unwrap();
+    [
+        (ac.gpu_min_freq, ac.gpu_max_freq, ac.gpu_boost_freq),
+        (
+            ac.second_stage_gpu_min_freq,
+            ac.second_stage_gpu_max_freq,
+            ac.second_stage_gpu_boost_freq,
+        ),
+        (bat.gpu_min_freq, bat.gpu_max_freq, bat.gpu_boost_freq),
+        (
+            bat.second_stage_gpu_min_freq,
+            bat.second_stage_gpu_max_freq,
+            bat.second_stage_gpu_boost_freq,
+        ),
+    ]
+    .iter()
+    .filter(|(min, max, boost)| min.is_some() || max.is_some() || boost.is_some())
+    .cloned()
+    .collect()
+}
+
+fn check_gpu_freqs(config: &Config) {
+    let configured = get_configured_gpu_freqs(config);
+    if configured.is_empty() {
+        return;
+    }
+    let gpus = gpu::get_gpus(gpu::DRM_PATH);
+    if gpus.is_empty() {
+        eprintln!(
+            "[{}] Error: GPU frequencies specified in config file but no Intel GPU found!",
+            "!".red()
+        );
+        exit_config_error()
+    }
+    for (min, max, boost) in configured {
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                eprintln!(
+                    "[{}] Error: GPU minimum frequency {}MHz is above the maximum frequency {}MHz!",
+                    "!".red(),
+                    min,
+                    max
+                );
+                exit_config_error()
+            }
+        }
+        for gpu in gpus.iter() {
+            let (rpn, rp0) = match gpu::get_gpu_freq_range(gpu) {
+                Some(range) => range,
+                None => continue,
+            };
+            for freq in [min, max, boost].iter().flatten() {
+                if *freq < rpn || *freq > rp0 {
+                    eprintln!(
+                        "[{}] Error: GPU frequency {}MHz for {} is outside of {}-{}MHz!",
+                        "!".red(),
+                        freq,
+                        gpu.name,
+                        rpn,
+                        rp0
+                    );
+                    exit_config_error()
+                }
+            }
+        }
+    }
+}
+
+fn get_configured_core_classes(config: &Config) -> Vec<(CoreType, &CoreClassConfig)> {
+    let ac = config.plugged_in.as_ref().unwrap();
+    let bat = config.on_battery.as_ref().unwrap();
+    [
+        (CoreType::Performance, ac.performance_cores.as_ref()),
+        (CoreType::Efficiency, ac.efficiency_cores.as_ref()),
+        (CoreType::Performance, bat.performance_cores.as_ref()),
+        (CoreType::Efficiency, bat.efficiency_cores.as_ref()),
+    ]
+    .iter()
+    .filter_map(|(core_type, class)| class.map(|class| (*core_type, class)))
+    .collect()
+}
+
+/*
+    Core classes of a profile have to be reset when it ends, even if the
+    power sources have none
+*/
+fn uses_core_classes(config: &Config) -> bool {
+    !get_configured_core_classes(config).is_empty()
+        || config.profiles.values().any(|profile| {
+            profile.performance_cores.is_some() || profile.efficiency_cores.is_some()
+        })
+}
+
+/*
+    Online CPUs of the core classes of the current power source that have
+    the setting
+*/
+fn get_core_class_cpus(
+    config: &Config,
+    ac_power: bool,
+    has_setting: fn(&CoreClassConfig) -> bool,
+) -> Vec<i32> {
+    let (performance, efficiency) = if ac_power {
+        let ac = config.plugged_in.as_ref().unwrap();
+        (ac.performance_cores.as_ref(), ac.efficiency_cores.as_ref())
+    } else {
+        let bat = config.on_battery.as_ref().unwrap();
+        (
+            bat.performance_cores.as_ref(),
+            bat.efficiency_cores.as_ref(),
+        )
+    };
+    if performance.is_none() && efficiency.is_none() {
+        return Vec::new();
+    }
+    let core_types = topology::get_core_types(&get_online_cpus());
+    [
+        (CoreType::Performance, performance),
+        (CoreType::Efficiency, efficiency),
+    ]
+    .iter()
+    .filter(|(_, class)| matches!(class, Some(class) if has_setting(class)))
+    .flat_map(|(core_type, _)| topology::get_cpus_of_type(&core_types, *core_type))
+    .collect()
+}
+
+fn check_core_class_config(config: &Config, avail_govs: &[String]) {
+    let core_classes = get_configured_core_classes(config);
+    if core_classes.is_empty() {
+        return;
+    }
+    let core_types = topology::get_core_types(&get_online_cpus());
+    if !topology::is_hybrid(&core_types) {
+        eprintln!(
+            "[{}] Error: Core class options specified in config file but no hybrid CPU found!",
+            "!".red()
+        );
+        exit_config_error()
+    }
+    let path = "/sys/devices/system/cpu/cpu";
+    for (core_type, class) in core_classes.iter() {
+        let cpus = topology::get_cpus_of_type(&core_types, *core_type);
+        if cpus.is_empty() {
+            eprintln!(
+                "[{}] Error: Core class options specified for {} but none are online!",
+                "!".red(),
+                core_type.name().to_lowercase()
+            );
+            exit_config_error()
+        }
+        if let Some(governor) = class.governor.as_ref() {
+            if !avail_govs.iter().any(|i| i.trim() == governor) {
+                eprintln!(
+                    "[{}] Error: Governor '{}' for {} isn't available!",
+                    "!".red(),
+                    governor,
+                    core_type.name().to_lowercase()
+                );
+                exit_config_error()
+            }
+        }
+        if let Some(epp) = class.epp.as_ref() {
+            let available = std::fs::read_to_string(format!(
+                "{}{}/cpufreq/energy_performance_available_preferences",
+                path, cpus[0]
+            ))
+            .unwrap_or_default();
+            if !available.split_whitespace().any(|i| i == epp) {
+                eprintln!(
+                    "[{}] Error: EPP '{}' for {} isn't available!\n     'cat {}{}/cpufreq/energy_performance_available_preferences' to see available preferences",
+                    "!".red(),
+                    epp,
+                    core_type.name().to_lowercase(),
+                    path,
+                    cpus[0]
+                );
+                exit_config_error()
+            }
+        }
+        if let Some(max_freq) = class.max_freq {
+            for cpu in cpus.iter() {
+                let (min, max) = get_cpu_freq_limits(*cpu);
+                if max_freq < min || max_freq > max {
+                    eprintln!(
+                        "[{}] Error: max_freq {}MHz for CPU{} is outside of {}-{}MHz!",
+                        "!".red(),
+                        max_freq,
+                        cpu,
+                        min,
+                        max
+                    );
+                    exit_config_error()
+                }
+            }
+        }
+    }
+}
+
+fn uses_epp(config: &Config) -> bool {
+    config.plugged_in.as_ref().unwrap().epp.is_some()
+        || config.on_battery.as_ref().unwrap().epp.is_some()
+}
+
+fn uses_perf_pct(config: &Config) -> bool {
+    let ac = config.plugged_in.as_ref().unwrap();
+    let bat = config.on_battery.as_ref().unwrap();
+    [
+        ac.min_perf_pct,
+        ac.max_perf_pct,
+        ac.second_stage_min_perf_pct,
+        ac.second_stage_max_perf_pct,
+        bat.min_perf_pct,
+        bat.max_perf_pct,
+        bat.second_stage_min_perf_pct,
+        bat.second_stage_max_perf_pct,
+    ]
+    .iter()
+    .any(|pct| pct.is_some())
+}
+
+fn uses_device_power(config: &Config) -> bool {
+    config.plugged_in.as_ref().unwrap().devices.is_some()
+        || config.on_battery.as_ref().unwrap().devices.is_some()
+}
+
+fn uses_wifi_power_save(config: &Config) -> bool {
+    config
+        .plugged_in
+        .as_ref()
+        .unwrap()
+        .wifi_power_save
+        .is_some()
+        || config
+            .on_battery
+            .as_ref()
+            .unwrap()
+            .wifi_power_save
+            .is_some()
+}
+
+fn uses_smt_control(config: &Config) -> bool {
+    let bat = config.on_battery.as_ref().unwrap();
+    bat.disable_smt_below.is_some() || bat.disable_smt_on_low_load.unwrap_or(false)
+}
+
+fn get_configured_power_limits(config: &Config) -> Vec<(&'static str, f32)> {
+    let ac = config.plugged_in.as_ref().unwrap();
+    let bat = config.on_battery.as_ref().unwrap();
+    [
+        (rapl::LONG_TERM, ac.pl1),
+        (rapl::SHORT_TERM, ac.pl2),
+        (rapl::LONG_TERM, ac.second_stage_pl1),
+        (rapl::SHORT_TERM, ac.second_stage_pl2),
+        (rapl::LONG_TERM, bat.pl1),
+        (rapl::SHORT_TERM, bat.pl2),
+        (rapl::LONG_TERM, bat.second_stage_pl1),
+        (rapl::SHORT_TERM, bat.second_stage_pl2),
+    ]
+    .iter()
+    .filter_map(|(constraint, limit)| limit.map(|watts| (*constraint, watts)))
+    .collect()
+}
+
+pub fn get_config() -> Config {
+    let decoded_config: Config = match toml::from_str(
+        &std::fs::read_to_string("/etc/yablo/config.toml")
+            .expect("Something went wrong reading config file"),
+    ) {
+        Ok(c) => c,
+        Err(x) => {
+            eprintln!("[{}] Error: {}", "!".red(), x);
+            std::process::exit(1)
+        }
+    };
+    decoded_config
+}
+
+/*
+    System info collection
+*/
+pub struct SystemInfo {
+    pub temperature: f32,
+    pub ac_power: bool,
+    pub loadavg: f32,
+    pub loadperc: f32,
+    pub mem_usage: (u64, u64),
+    pub turbo_invert: bool,
+    pub turbo_avail: bool,
+    pub cpu_freqs: Vec<(i32, i32)>,
+    pub core_types: Vec<(i32, CoreType)>,
+    pub battery_capacity: u8,
+    pub power_limits: Vec<(Option<f32>, Option<f32>)>,
+    pub smt: Option<bool>,
+    pub driver: DriverInfo,
+    pub perf_pct: Option<(u8, u8)>,
+    pub epb: Option<u8>,
+    pub lid_closed: Option<bool>,
+    pub external_displays: Vec<String>,
+    pub idle_seconds: Option<u64>,
+    pub schedule: Option<String>,
+    // load of excluded and down-weighted cgroups left out of loadavg/loadperc
+    pub ignored_load: Option<CgroupLoad>,
+}
+
+pub fn get_sys_info(sys: &System, turbo_avail: bool, invert: bool) -> SystemInfo {
+    SystemInfo {
+        loadavg: match sys.load_average() {
+            Ok(loadavg) => loadavg.one,
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                std::process::exit(1)
+            }
+        },
+        temperature: match sys.cpu_temp() {
+            Ok(temp) => temp,
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                std::process::exit(1)
+            }
+        },
+        ac_power: on_ac_power(),
+        loadperc: match sys.cpu_load_aggregate() {
+            Ok(cpu) => {
+                std::thread::sleep(std::time::Duration::from_millis(1000));
+                let cpu = cpu.done().unwrap();
+                cpu.user * 100.0
+                // cpu.system * 100.0
+            }
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                std::process::exit(1)
+            }
+        },
+        turbo_avail: turbo_avail,
+        turbo_invert: invert,
+        cpu_freqs: get_cpu_freq(),
+        core_types: topology::get_core_types(&get_online_cpus()),
+        mem_usage: match sys.memory() {
+            Ok(mem) => (mem.total.as_u64(), mem.free.as_u64()),
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                std::process::exit(1)
+            }
+        },
+        battery_capacity: get_battery_percentage(),
+        power_limits: rapl::get_power_limits(),
+        smt: get_smt(),
+        driver: driver::get_driver_info(),
+        perf_pct: get_perf_pct(),
+        epb: epb::get_epb(0),
+        lid_closed: None,
+        external_displays: Vec::new(),
+        idle_seconds: None,
+        schedule: None,
+        ignored_load: None,
+    }
+}
+
+fn get_battery_percentage() -> u8 {
+    let bat0_path = "/sys/class/power_supply/BAT0/capacity";
+    let bat1_path = "/sys/class/power_supply/BAT1/capacity";
+    let bat0_avail = match std::fs::metadata(bat0_path) {
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    let bat1_avail = match std::fs::metadata(bat1_path) {
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    let batteries = (bat0_avail, bat1_avail);
+
+    match batteries {
+        (true, true) => {
+            let bat0_capacity = match std::fs::read_to_string(bat0_path) {
+                Ok(capacity) => capacity.replace('\n', "").parse::<u8>().unwrap(),
+                Err(_) => {
+                    eprintln!("[{}] Error: Reading battery capacity for BAT0 (\'/sys/class/power_supply/BAT0/capacity\')", "!".red());
+                    std::process::exit(1);
+                }
+            };
+            let bat1_capacity = match std::fs::read_to_string(bat1_path) {
+                Ok(capacity) => capacity.replace('\n', "").parse::<u8>().unwrap(),
+                Err(_) => {
+                    eprintln!("[{}] Error: Reading battery capacity for BAT1 (\'/sys/class/power_supply/BAT1/capacity\')", "!".red());
+                    std::process::exit(1);
+                }
+            };
+            return (bat0_capacity + bat1_capacity) / 2;
+        }
+        (true, false) => {
+            match std::fs::read_to_string(bat0_path) {
+                Ok(capacity) => return capacity.replace('\n', "").parse::<u8>().unwrap(),
+                Err(_) => {
+                    eprintln!("[{}] Error: Reading battery capacity for BAT0 (\'/sys/class/power_supply/BAT0/capacity\')", "!".red());
+                    std::process::exit(1);
+                }
+            };
+        }
+        (false, true) => {
+            match std::fs::read_to_string(bat1_path) {
+                Ok(capacity) => return capacity.replace('\n', "").parse::<u8>().unwrap(),
+                Err(_) => {
+                    eprintln!("[{}] Error: Reading battery capacity for BAT1 (\'/sys/class/power_supply/BAT1/capacity\')", "!".red());
+                    std::process::exit(1);
+                }
+            };
+        }
+        (false, false) => {
+            return 101;
+        }
+    }
+}
 
 fn on_ac_power() -> bool {
     let bat0_path = "/sys/class/power_supply/BAT0/status";
@@ -355,16 +1614,17 @@ pub fn check_root() {
 }
 
 pub fn check_turbo_availability() -> (bool, bool) {
-    let p_state = "/sys/devices/system/cpu/intel_pstate/no_turbo";
-    let cpufreq = "/sys/devices/system/cpu/cpufreq/boost";
-
-    match std::fs::metadata(p_state) {
-        Ok(_) => return (true, true),
-        Err(_) => match std::fs::metadata(cpufreq) {
-            Ok(_) => return (true, false),
-            Err(_) => return (true, false),
-        },
-    };
+    match driver::get_turbo_interface(driver::SYSFS_PATH) {
+        Some(TurboInterface::NoTurbo) => (true, true),
+        Some(TurboInterface::Boost) => (true, false),
+        None => {
+            println!(
+                "[{}] No turbo found! Turbo settings are ignored.",
+                "!".yellow()
+            );
+            (false, false)
+        }
+    }
 }
 
 pub fn check_daemon() {
@@ -380,19 +1640,134 @@ pub fn check_daemon() {
         );
         std::process::exit(1)
     }
-}
-
-pub fn check_log() {
-    let path = "/var/log/yablo.log";
-    match std::fs::metadata(path) {
-        Ok(_) => (),
-        Err(_) => match std::fs::File::create(path) {
-            Ok(_) => (),
-            Err(x) => {
-                eprintln!("[{}] Error: {}", "!".red(), x);
-                std::process::exit(1);
+}
+
+pub fn check_log() {
+    let path = "/var/log/yablo.log";
+    match std::fs::metadata(path) {
+        Ok(_) => (),
+        Err(_) => match std::fs::File::create(path) {
+            Ok(_) => (),
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/*
+    Snapshot and exit handling
+*/
+
+extern "C" fn request_exit(_: i32) {
+    EXIT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn register_exit_handler() {
+    let action = SigAction::new(
+        SigHandler::Handler(request_exit),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP].iter() {
+        match unsafe { sigaction(*signal, &action) } {
+            Ok(_) => (),
+            Err(x) => {
+                eprintln!("[{}] Error: {}", "!".red(), x);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+pub fn exit_requested() -> bool {
+    EXIT_REQUESTED.load(Ordering::SeqCst)
+}
+
+/*
+    Profiles are saved first, so the base config decides the restore order
+*/
+pub fn take_snapshot(config: &Config) -> Snapshot {
+    let mut snapshot = Snapshot::default();
+    for (_, _, profile_config) in get_profile_configs(config) {
+        save_config_state(&profile_config, &mut snapshot);
+    }
+    save_config_state(config, &mut snapshot);
+    snapshot
+}
+
+fn save_config_state(config: &Config, snapshot: &mut Snapshot) {
+    tunables::save_tunables(&get_configured_tunables(config), snapshot);
+    cgroup::save_throttles(&get_configured_throttles(config), snapshot);
+    if !get_configured_power_limits(config).is_empty() {
+        rapl::save_power_limits(snapshot);
+    }
+    if config.plugged_in.as_ref().unwrap().epb.is_some()
+        || config.on_battery.as_ref().unwrap().epb.is_some()
+    {
+        for cpu in get_online_cpus() {
+            snapshot.save(&epb::get_epb_path(cpu));
+        }
+    }
+    if !get_configured_gpu_freqs(config).is_empty() {
+        gpu::save_gpu_freqs(&gpu::get_gpus(gpu::DRM_PATH), snapshot);
+    }
+    if uses_wifi_power_save(config) {
+        for interface in wifi::get_wifi_interfaces().unwrap_or_default() {
+            if let Ok(enabled) = wifi::get_power_save(&interface) {
+                snapshot.save_wifi_power_save(&interface, enabled);
             }
-        },
+        }
+    }
+    if uses_device_power(config) {
+        devices::save_device_states(&config.devices.clone().unwrap_or_default(), snapshot);
+    }
+    if uses_perf_pct(config) {
+        snapshot.save(MIN_PERF_PCT);
+        snapshot.save(MAX_PERF_PCT);
+    }
+    if uses_epp(config) {
+        for cpu in get_online_cpus() {
+            let path = get_epp_path(cpu);
+            if std::fs::metadata(&path).is_ok() {
+                snapshot.save(&path);
+            }
+        }
+    }
+    if !get_configured_core_classes(config).is_empty() {
+        for cpu in get_online_cpus() {
+            let path = format!("/sys/devices/system/cpu/cpu{}/cpufreq", cpu);
+            snapshot.save(&format!("{}/scaling_max_freq", path));
+            if std::fs::metadata(format!("{}/energy_performance_preference", path)).is_ok() {
+                snapshot.save(&format!("{}/energy_performance_preference", path));
+            }
+        }
+    }
+    // cpu hotplug state is restored after SMT, which may already bring siblings back
+    if config.on_battery.as_ref().unwrap().parked_cores.unwrap() > 0 {
+        for cpu in get_parkable_cpus() {
+            snapshot.save(&format!("/sys/devices/system/cpu/cpu{}/online", cpu));
+        }
+    }
+    if uses_smt_control(config) {
+        snapshot.save("/sys/devices/system/cpu/smt/control");
+    }
+    // restored first, the per-CPU values above only exist in the original mode
+    if config
+        .plugged_in
+        .as_ref()
+        .unwrap()
+        .amd_pstate_mode
+        .is_some()
+        || config
+            .on_battery
+            .as_ref()
+            .unwrap()
+            .amd_pstate_mode
+            .is_some()
+    {
+        snapshot.save(driver::AMD_PSTATE_STATUS);
     }
 }
 
@@ -426,33 +1801,48 @@ fn get_turbo(invert: bool) -> bool {
     }
 }
 
-fn set_turbo(new_state: bool, invert: bool) {
+fn set_turbo(new_state: bool, invert: bool) -> std::io::Result<()> {
     if invert {
         let p_state = "/sys/devices/system/cpu/intel_pstate/no_turbo";
         let output = if new_state { "0" } else { "1" };
-        match std::fs::write(p_state, output) {
-            Ok(_) => (),
-            Err(_) => {
-                eprintln!(
-                    "[{}] Error: couldn't write new turbo state. exit.",
-                    "!".red()
-                );
-                std::process::exit(1);
-            }
-        }
+        sysfs::write(p_state, output)
     } else {
         let cpufreq = "/sys/devices/system/cpu/cpufreq/boost";
         let output = if new_state { "1" } else { "0" };
-        match std::fs::write(cpufreq, output) {
-            Ok(_) => (),
-            Err(_) => {
-                eprintln!(
-                    "[{}] Error: couldn't write new turbo state. exit.",
-                    "!".red()
-                );
-                std::process::exit(1);
-            }
-        }
+        sysfs::write(cpufreq, output)
+    }
+}
+
+/*
+    Turbo is an optional capability: without a turbo interface the decision path
+    only sets the governor
+*/
+fn apply_turbo(new_state: bool, sys_info: &SystemInfo) -> std::io::Result<()> {
+    if !sys_info.turbo_avail {
+        return Ok(());
+    }
+    set_turbo(new_state, sys_info.turbo_invert)?;
+    if new_state {
+        println!("[{}] Turbo activated", "+".dark_green());
+    } else {
+        println!("[{}] Turbo deactivated", "+".dark_green());
+    }
+    Ok(())
+}
+
+fn suggest_turbo(new_state: bool, sys_info: &SystemInfo) {
+    if !sys_info.turbo_avail {
+        return;
+    }
+    if new_state {
+        println!("[{}] Suggesting setting Turbo on", "+".dark_green());
+    } else {
+        println!("[{}] Suggesting setting Turbo off", "+".dark_green());
+    }
+    if get_turbo(sys_info.turbo_invert) {
+        println!("[{}] Turbo is currently on", "+".dark_green());
+    } else {
+        println!("[{}] Turbo is currently off", "+".dark_green());
     }
 }
 
@@ -472,34 +1862,373 @@ fn get_governor() -> String {
         .replace('\n', "")
 }
 
-fn set_governor(governor: &str, num_cpus: i32) {
+/*
+    CPUs in skip keep their governor, e.g. the ones of a core class with its
+    own governor. Switching them back and forth would reinitialise their
+    cpufreq policy on every run
+*/
+fn set_governor(governor: &str, skip: &[i32]) -> std::io::Result<()> {
+    for k in get_online_cpus()
+        .into_iter()
+        .filter(|cpu| !skip.contains(cpu))
+    {
+        set_cpu_governor(k, governor)?;
+    }
+    Ok(())
+}
+
+fn set_cpu_governor(cpu: i32, governor: &str) -> std::io::Result<()> {
     let path = "/sys/devices/system/cpu/cpu";
-    for k in 0..num_cpus {
-        match std::fs::write(
-            format!("{}{}{}", path, k, "/cpufreq/scaling_governor"),
-            governor,
-        ) {
+    sysfs::write(
+        &format!("{}{}{}", path, cpu, "/cpufreq/scaling_governor"),
+        governor,
+    )
+}
+
+const EPP_AVAILABLE: &str =
+    "/sys/devices/system/cpu/cpu0/cpufreq/energy_performance_available_preferences";
+
+fn get_epp_path(cpu: i32) -> String {
+    format!(
+        "/sys/devices/system/cpu/cpu{}/cpufreq/energy_performance_preference",
+        cpu
+    )
+}
+
+/*
+    EPP of all CPUs, or the startup value if none is set. CPUs of a core
+    class with its own EPP are skipped, writing both would flip them twice
+    per run
+*/
+fn set_epp(epp: Option<&str>, skip: &[i32], snapshot: &Snapshot) {
+    for cpu in get_online_cpus()
+        .into_iter()
+        .filter(|cpu| !skip.contains(cpu))
+    {
+        match snapshot.write_or_restore(&get_epp_path(cpu), epp) {
             Ok(_) => (),
-            Err(x) => {
-                eprintln!("[{}] Error: {}. exit.", "!".red(), x);
-                std::process::exit(1);
-            }
+            Err(x) => eprintln!(
+                "[{}] Warning: couldn't set EPP on CPU{}: {}",
+                "!".yellow(),
+                cpu,
+                x
+            ),
+        }
+    }
+    if let Some(epp) = epp {
+        println!("[{}] Using '{}' EPP", "+".dark_green(), epp);
+    }
+}
+
+/*
+    intel_pstate refuses EPP changes with the performance governor, so a failed
+    write only gets reported
+*/
+fn set_cpu_epp(cpu: i32, epp: &str) {
+    let path = "/sys/devices/system/cpu/cpu";
+    match std::fs::write(
+        format!(
+            "{}{}{}",
+            path, cpu, "/cpufreq/energy_performance_preference"
+        ),
+        epp,
+    ) {
+        Ok(_) => (),
+        Err(x) => eprintln!(
+            "[{}] Warning: couldn't set EPP '{}' on CPU{}: {}",
+            "!".yellow(),
+            epp,
+            cpu,
+            x
+        ),
+    };
+}
+
+fn get_cpu_freq_limits(cpu: i32) -> (u32, u32) {
+    let path = "/sys/devices/system/cpu/cpu";
+    let read = |file: &str| -> u32 {
+        std::fs::read_to_string(format!("{}{}/cpufreq/{}", path, cpu, file))
+            .expect("Something went wrong reading frequency limits")
+            .trim()
+            .parse::<u32>()
+            .unwrap()
+            / 1000
+    };
+    (read("cpuinfo_min_freq"), read("cpuinfo_max_freq"))
+}
+
+fn set_cpu_max_freq(cpu: i32, max_freq: Option<u32>, snapshot: &Snapshot) -> std::io::Result<()> {
+    let path = format!(
+        "/sys/devices/system/cpu/cpu{}/cpufreq/scaling_max_freq",
+        cpu
+    );
+    let output = match max_freq {
+        Some(max_freq) => (max_freq * 1000).to_string(),
+        None => match snapshot.original(&path) {
+            Some(value) => value.to_string(),
+            None => return Ok(()),
+        },
+    };
+    sysfs::write(&path, &output)
+}
+
+fn set_power_limits(
+    pl1: Option<f32>,
+    pl2: Option<f32>,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
+    rapl::set_power_limit(rapl::LONG_TERM, pl1, snapshot)?;
+    rapl::set_power_limit(rapl::SHORT_TERM, pl2, snapshot)?;
+    if let Some(watts) = pl1 {
+        println!(
+            "[{}] Power limit PL1 set to {:.1}W",
+            "+".dark_green(),
+            watts
+        );
+    }
+    if let Some(watts) = pl2 {
+        println!(
+            "[{}] Power limit PL2 set to {:.1}W",
+            "+".dark_green(),
+            watts
+        );
+    }
+    Ok(())
+}
+
+fn set_gpu_freqs(min: Option<u32>, max: Option<u32>, boost: Option<u32>, snapshot: &Snapshot) {
+    for gpu in gpu::get_gpus(gpu::DRM_PATH) {
+        gpu::set_gpu_freqs(&gpu, min, max, boost, snapshot);
+    }
+    for (name, freq) in [("minimum", min), ("maximum", max), ("boost", boost)] {
+        if let Some(freq) = freq {
+            println!(
+                "[{}] GPU {} frequency set to {}MHz",
+                "+".dark_green(),
+                name,
+                freq
+            );
+        }
+    }
+}
+
+/*
+    Interfaces without a setting for the current power source get their
+    state from the startup snapshot back
+*/
+fn set_wifi_power_save(enabled: Option<bool>, snapshot: &Snapshot) {
+    for interface in wifi::get_wifi_interfaces().unwrap_or_default() {
+        let enabled = match enabled.or_else(|| snapshot.original_wifi_power_save(&interface)) {
+            Some(enabled) => enabled,
+            None => continue,
         };
+        match wifi::get_power_save(&interface) {
+            Ok(current) if current == enabled => (),
+            _ => match wifi::set_power_save(&interface, enabled) {
+                Ok(_) => println!(
+                    "[{}] Wi-Fi power save on '{}' {}",
+                    "+".dark_green(),
+                    interface,
+                    if enabled { "enabled" } else { "disabled" }
+                ),
+                Err(x) => eprintln!(
+                    "[{}] Warning: Can't set power save of '{}': {}",
+                    "!".yellow(),
+                    interface,
+                    x
+                ),
+            },
+        }
+    }
+}
+
+pub fn parse_cpu_list(list: &str) -> Vec<i32> {
+    let mut cpus: Vec<i32> = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-').map(|bound| bound.parse::<i32>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(first)), None) => cpus.push(first),
+            (Some(Ok(first)), Some(Ok(last))) => cpus.extend(first..=last),
+            _ => (),
+        }
+    }
+    cpus
+}
+
+/*
+    CPUs can go offline at runtime (e.g. SMT siblings), so they are enumerated
+    on every call instead of relying on the count at startup
+*/
+fn get_online_cpus() -> Vec<i32> {
+    let path = "/sys/devices/system/cpu/online";
+    parse_cpu_list(
+        &std::fs::read_to_string(path).expect("Something went wrong reading online cpus"),
+    )
+}
+
+fn set_amd_pstate_mode(mode: &str) -> std::io::Result<()> {
+    driver::set_amd_pstate_mode(mode).map_err(|x| {
+        std::io::Error::new(
+            x.kind(),
+            format!("couldn't switch amd-pstate to '{}' mode: {}", mode, x),
+        )
+    })?;
+    println!("[{}] Using amd-pstate '{}' mode", "+".dark_green(), mode);
+    Ok(())
+}
+
+/*
+    Without a value for the current power source the one from the startup
+    snapshot is written back. Unchanged values aren't written, and without
+    epb in the config nothing is saved and nothing written
+*/
+fn set_epb(value: Option<u8>, snapshot: &Snapshot) -> std::io::Result<()> {
+    let output = value.map(|value| value.to_string());
+    for cpu in get_online_cpus() {
+        let path = epb::get_epb_path(cpu);
+        snapshot
+            .write_or_restore(&path, output.as_deref())
+            .map_err(|x| {
+                std::io::Error::new(x.kind(), format!("couldn't write '{}': {}", path, x))
+            })?;
+    }
+    if let Some(value) = value {
+        println!("[{}] EPB set to {}", "+".dark_green(), value);
+    }
+    Ok(())
+}
+
+fn get_smt() -> Option<bool> {
+    let path = "/sys/devices/system/cpu/smt/control";
+    match std::fs::read_to_string(path) {
+        Ok(control) => match control.trim() {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        },
+        Err(_) => None,
+    }
+}
+
+fn set_smt(new_state: bool) -> std::io::Result<()> {
+    let path = "/sys/devices/system/cpu/smt/control";
+    let output = if new_state { "on" } else { "off" };
+    sysfs::write(path, output)
+}
+
+/*
+    Online CPUs that support hotplug, in the order they get parked: performance
+    cores of hybrid CPUs first, then the highest-numbered ones. The boot CPU
+    is never parked
+*/
+fn get_parkable_cpus() -> Vec<i32> {
+    let path = "/sys/devices/system/cpu/cpu";
+    let performance_cpus = topology::get_cpus_of_type(
+        &topology::get_core_types(&get_online_cpus()),
+        CoreType::Performance,
+    );
+    let mut cpus: Vec<i32> = get_online_cpus()
+        .into_iter()
+        .filter(|cpu| *cpu != 0)
+        .filter(|cpu| std::fs::metadata(format!("{}{}/online", path, cpu)).is_ok())
+        .collect();
+    cpus.sort_by_key(|cpu| (!performance_cpus.contains(cpu), -cpu));
+    cpus
+}
+
+fn set_cpu_online(cpu: i32, online: bool) -> std::io::Result<()> {
+    let path = format!("/sys/devices/system/cpu/cpu{}/online", cpu);
+    let output = if online { "1" } else { "0" };
+    sysfs::write(&path, output)
+}
+
+fn park_cpus(count: u32, parked_cpus: &mut Vec<i32>) -> std::io::Result<()> {
+    let missing = (count as usize).saturating_sub(parked_cpus.len());
+    for cpu in get_parkable_cpus().into_iter().take(missing) {
+        set_cpu_online(cpu, false)?;
+        parked_cpus.push(cpu);
+    }
+    Ok(())
+}
+
+fn unpark_cpus(parked_cpus: &mut Vec<i32>) -> std::io::Result<()> {
+    let online = get_online_cpus();
+    while let Some(cpu) = parked_cpus.pop() {
+        if !online.contains(&cpu) {
+            set_cpu_online(cpu, true)?;
+        }
+    }
+    Ok(())
+}
+
+const MIN_PERF_PCT: &str = "/sys/devices/system/cpu/intel_pstate/min_perf_pct";
+const MAX_PERF_PCT: &str = "/sys/devices/system/cpu/intel_pstate/max_perf_pct";
+
+fn get_perf_pct() -> Option<(u8, u8)> {
+    let read = |path: &str| -> Option<u8> {
+        match std::fs::read_to_string(path) {
+            Ok(value) => value.trim().parse::<u8>().ok(),
+            Err(_) => None,
+        }
+    };
+    Some((read(MIN_PERF_PCT)?, read(MAX_PERF_PCT)?))
+}
+
+/*
+    intel_pstate rejects a minimum above the current maximum and vice versa, so
+    the order of the writes depends on the direction of the change. Without a
+    configured value the one from the startup snapshot is used
+*/
+fn set_perf_pct(min: Option<u8>, max: Option<u8>, snapshot: &Snapshot) -> std::io::Result<()> {
+    let (current_min, _) = match get_perf_pct() {
+        Some(current) => current,
+        None => return Ok(()),
+    };
+    let new_min = match min.map(|min| min.to_string()) {
+        Some(min) => min,
+        None => match snapshot.original(MIN_PERF_PCT) {
+            Some(min) => min.to_string(),
+            None => return Ok(()),
+        },
+    };
+    let new_max = match max.map(|max| max.to_string()) {
+        Some(max) => max,
+        None => match snapshot.original(MAX_PERF_PCT) {
+            Some(max) => max.to_string(),
+            None => return Ok(()),
+        },
+    };
+    let writes = if new_max.parse::<u8>().unwrap_or(100) < current_min {
+        [(MIN_PERF_PCT, &new_min), (MAX_PERF_PCT, &new_max)]
+    } else {
+        [(MAX_PERF_PCT, &new_max), (MIN_PERF_PCT, &new_min)]
+    };
+    for (path, value) in writes.iter() {
+        sysfs::write(path, value)?;
+    }
+    if min.is_some() || max.is_some() {
+        println!(
+            "[{}] Performance range set to {}-{}%",
+            "+".dark_green(),
+            new_min,
+            new_max
+        );
     }
+    Ok(())
 }
 
-fn get_cpu_freq(num_cpus: i32) -> Vec<i32> {
+fn get_cpu_freq() -> Vec<(i32, i32)> {
     let path = "/sys/devices/system/cpu/cpu";
     let path_append = "/cpufreq/scaling_cur_freq";
-    let mut vec: Vec<i32> = Vec::new();
-    for cpu in 0..num_cpus {
+    let mut vec: Vec<(i32, i32)> = Vec::new();
+    for cpu in get_online_cpus() {
         let curr_freq = std::fs::read_to_string(format!("{}{}{}", path, cpu, path_append))
             .expect("Something went wrong reading current frequency")
             .replace('\n', "")
             .parse::<i32>()
             .unwrap()
             / 1000;
-        vec.push(curr_freq);
+        vec.push((cpu, curr_freq));
     }
     vec
 }
@@ -508,7 +2237,11 @@ fn get_cpu_freq(num_cpus: i32) -> Vec<i32> {
     Printing system info and optimize
 */
 
-pub fn print_info(sys_info: &SystemInfo, terminalout: &mut std::io::Stdout) {
+pub fn print_info(
+    sys_info: &SystemInfo,
+    energy: &[EnergyState],
+    terminalout: &mut std::io::Stdout,
+) {
     match terminalout.execute(crossterm::terminal::Clear(
         crossterm::terminal::ClearType::All,
     )) {
@@ -525,9 +2258,11 @@ pub fn print_info(sys_info: &SystemInfo, terminalout: &mut std::io::Stdout) {
         "\u{2591}".repeat(18).blue()
     );
     println!("{}\n", "\u{2591}".repeat(50).blue());
-    if !sys_info.turbo_avail {
-        println!("[{}] No turbo found!", "!".yellow());
-    }
+    println!(
+        "[{}] Scaling driver  : {}",
+        "+".dark_green(),
+        format_driver_info(&sys_info.driver)
+    );
     if sys_info.ac_power {
         println!("[{}] Currently running on AC power", "+".dark_green());
         if sys_info.battery_capacity != 101 {
@@ -547,35 +2282,124 @@ pub fn print_info(sys_info: &SystemInfo, terminalout: &mut std::io::Stdout) {
             );
         }
     }
-    println!(
-        "[{}] CPU temp        : {}°C",
-        "+".dark_green(),
-        sys_info.temperature
-    );
-    println!(
-        "[{}] Memory usage    : {:.2}GB/{:.2}GB",
-        "+".dark_green(),
-        (sys_info.mem_usage.0 - sys_info.mem_usage.1) as f32 / 1e9,
-        sys_info.mem_usage.0 as f32 / 1e9
-    );
-    println!(
-        "[{}] System load     : {:.2}",
-        "+".dark_green(),
-        sys_info.loadavg
-    );
-    println!(
-        "[{}] CPU usage       : {:.2}%",
-        "+".dark_green(),
-        sys_info.loadperc
-    );
-    println!("[{}] CPU frequencies : ", "+".dark_green());
-    for cpu in 0..sys_info.cpu_freqs.len() {
+    println!(
+        "[{}] CPU temp        : {}°C",
+        "+".dark_green(),
+        sys_info.temperature
+    );
+    println!(
+        "[{}] Memory usage    : {:.2}GB/{:.2}GB",
+        "+".dark_green(),
+        (sys_info.mem_usage.0 - sys_info.mem_usage.1) as f32 / 1e9,
+        sys_info.mem_usage.0 as f32 / 1e9
+    );
+    println!(
+        "[{}] System load     : {:.2}",
+        "+".dark_green(),
+        sys_info.loadavg
+    );
+    println!(
+        "[{}] CPU usage       : {:.2}%",
+        "+".dark_green(),
+        sys_info.loadperc
+    );
+    for (package, (pl1, pl2)) in sys_info.power_limits.iter().enumerate() {
+        println!(
+            "[{}] Power limits    : package{} PL1 {} / PL2 {}",
+            "+".dark_green(),
+            package,
+            format_power_limit(*pl1),
+            format_power_limit(*pl2)
+        );
+    }
+    if let Some((min, max)) = sys_info.perf_pct {
+        println!("[{}] Performance     : {}-{}%", "+".dark_green(), min, max);
+    }
+    if let Some(value) = sys_info.epb {
+        println!(
+            "[{}] EPB             : {} ({})",
+            "+".dark_green(),
+            value,
+            epb::get_epb_name(value).unwrap_or("custom")
+        );
+    }
+    if let Some(closed) = sys_info.lid_closed {
+        println!(
+            "[{}] Lid             : {}",
+            "+".dark_green(),
+            if closed { "closed" } else { "open" }
+        );
+    }
+    if !sys_info.external_displays.is_empty() {
+        println!(
+            "[{}] Ext. displays   : {}",
+            "+".dark_green(),
+            sys_info.external_displays.join(", ")
+        );
+    }
+    if let Some(schedule) = sys_info.schedule.as_ref() {
+        println!("[{}] Schedule        : {}", "+".dark_green(), schedule);
+    }
+    if let Some(ignored) = sys_info.ignored_load {
+        println!(
+            "[{}] Ignored load    : {:.2} / {:.2}%",
+            "+".dark_green(),
+            ignored.busy,
+            ignored.user
+        );
+    }
+    if let Some(seconds) = sys_info.idle_seconds {
+        println!(
+            "[{}] User idle       : {}min",
+            "+".dark_green(),
+            seconds / 60
+        );
+    }
+    if let Some(smt) = sys_info.smt {
+        println!(
+            "[{}] SMT             : {}",
+            "+".dark_green(),
+            if smt { "on" } else { "off" }
+        );
+    }
+    println!("[{}] CPU frequencies : ", "+".dark_green());
+    for core_type in [
+        CoreType::Performance,
+        CoreType::Efficiency,
+        CoreType::Uniform,
+    ]
+    .iter()
+    {
+        let cpus = topology::get_cpus_of_type(&sys_info.core_types, *core_type);
+        if cpus.is_empty() {
+            continue;
+        }
+        if topology::is_hybrid(&sys_info.core_types) {
+            println!("    {}:", core_type.name());
+        }
+        for (cpu, freq) in sys_info
+            .cpu_freqs
+            .iter()
+            .filter(|(cpu, _)| cpus.contains(cpu))
+        {
+            println!("    {} CPU{}: {:4}MHz", "\u{2218}".blue(), cpu, freq);
+        }
+    }
+    if !energy.is_empty() {
         println!(
-            "    {} CPU{}: {:4}MHz",
-            "\u{2218}".blue(),
-            cpu,
-            sys_info.cpu_freqs[cpu]
+            "[{}] Energy per state (package / battery): ",
+            "+".dark_green()
         );
+        for state in energy.iter() {
+            println!(
+                "    {} {}: {:.3}Wh / {:.3}Wh ({}min)",
+                "\u{2218}".blue(),
+                state.label,
+                state.package_wh,
+                state.battery_wh,
+                (state.seconds / 60.0).round()
+            );
+        }
     }
     println!("");
     match terminalout.flush() {
@@ -587,13 +2411,50 @@ pub fn print_info(sys_info: &SystemInfo, terminalout: &mut std::io::Stdout) {
     };
 }
 
+pub fn get_power_state(sys_info: &SystemInfo) -> String {
+    if sys_info.turbo_avail {
+        let turbo = if get_turbo(sys_info.turbo_invert) {
+            "on"
+        } else {
+            "off"
+        };
+        format!("{}, turbo {}", get_governor(), turbo)
+    } else {
+        get_governor()
+    }
+}
+
+fn format_driver_info(info: &DriverInfo) -> String {
+    let mut capabilities: Vec<&str> = Vec::new();
+    if let Some(mode) = info.mode.as_ref() {
+        capabilities.push(mode);
+    }
+    match info.turbo {
+        Some(TurboInterface::NoTurbo) => capabilities.push("turbo via no_turbo"),
+        Some(TurboInterface::Boost) => capabilities.push("turbo via boost"),
+        None => capabilities.push("no turbo control"),
+    }
+    if info.epp {
+        capabilities.push("EPP");
+    }
+    format!("{} ({})", info.name, capabilities.join(", "))
+}
+
+fn format_power_limit(limit: Option<f32>) -> String {
+    match limit {
+        Some(watts) => format!("{:.1}W", watts),
+        None => String::from("-"),
+    }
+}
+
 pub fn optimize_powerstate(
     config: &Config,
     sys_info: &SystemInfo,
-    cpus: i32,
     counter: &mut u32,
+    state: &mut DaemonState,
+    snapshot: &Snapshot,
     terminalout: &mut std::io::Stdout,
-) {
+) -> std::io::Result<()> {
     println!("{}", "\u{2591}".repeat(50).blue());
     println!(
         "{} Apply optimizations {}",
@@ -601,8 +2462,85 @@ pub fn optimize_powerstate(
         "\u{2591}".repeat(15).blue()
     );
     println!("{}\n", "\u{2591}".repeat(50).blue());
+    let resumed = std::mem::take(&mut state.resumed);
+    let power_transition = state.last_ac_power != Some(sys_info.ac_power);
+    let manual = get_manual_profile(state, sys_info.ac_power, true);
+    state.last_ac_power = Some(sys_info.ac_power);
+    let (effective_config, profile) = get_effective_config(config, sys_info, manual.as_ref());
+    if let Some(tier) = get_battery_tier(config, sys_info.ac_power, sys_info.battery_capacity) {
+        println!(
+            "[{}] Battery tier '{}' (below {}%)",
+            "+".dark_green(),
+            tier.profile,
+            tier.below
+        );
+    }
+    if let Some(profile) = profile {
+        println!("[{}] Using profile '{}'", "+".dark_green(), profile);
+    }
+    let effective_config =
+        apply_runtime_target(config, effective_config, sys_info, &mut state.runtime);
+    let config = &effective_config;
+    let amd_pstate_mode = if sys_info.ac_power {
+        config.plugged_in.as_ref().unwrap().amd_pstate_mode.as_ref()
+    } else {
+        config.on_battery.as_ref().unwrap().amd_pstate_mode.as_ref()
+    };
+    if let Some(mode) = amd_pstate_mode {
+        set_amd_pstate_mode(mode)?;
+    }
+    if uses_smt_control(config) {
+        set_smt_state(config, sys_info, &mut state.smt_hold)?;
+    }
+    set_parking_state(config, sys_info, &mut state.parked_cpus)?;
+    if resumed {
+        // firmware may have reset brightness, that isn't a manual change
+        state.backlight.forget_writes();
+    }
+    if power_transition || resumed {
+        // turbo_delay starts over for the new power source and after resume
+        *counter = 0;
+        let tunables = get_configured_tunables(config);
+        if !tunables.is_empty() {
+            tunables::set_tunables(&tunables, !sys_info.ac_power, snapshot);
+            println!(
+                "[{}] Kernel tunables {}",
+                "+".dark_green(),
+                if sys_info.ac_power {
+                    "restored"
+                } else {
+                    "applied"
+                }
+            );
+        }
+    }
+    let bat = config.on_battery.as_ref().unwrap();
+    if bat.backlight.is_some() || bat.low_battery_backlight.is_some() {
+        let backlight = if sys_info.ac_power {
+            None
+        } else if sys_info.battery_capacity <= bat.battery_threshold.unwrap() {
+            bat.low_battery_backlight.or(bat.backlight)
+        } else {
+            bat.backlight
+        };
+        state.backlight.update(backlight, power_transition);
+    }
+    let epb = if sys_info.ac_power {
+        config.plugged_in.as_ref().unwrap().epb.as_ref()
+    } else {
+        config.on_battery.as_ref().unwrap().epb.as_ref()
+    };
+    // also restores the startup value when a profile with epb ends
+    set_epb(epb.and_then(|epb| epb.value()), snapshot)?;
+    // without a user at the keyboard background load doesn't need high performance
+    let idle = is_user_idle(config, sys_info);
+    if idle {
+        println!("[{}] User idle", "+".dark_green());
+    }
     if sys_info.ac_power {
-        if sys_info.loadavg
+        if idle {
+            low_load_setting_ac(config, sys_info, counter, snapshot)?;
+        } else if sys_info.loadavg
             > config
                 .plugged_in
                 .as_ref()
@@ -610,7 +2548,7 @@ pub fn optimize_powerstate(
                 .loadavg_threshold
                 .unwrap()
         {
-            high_load_setting_ac(&config, &sys_info, cpus, counter);
+            high_load_setting_ac(config, sys_info, counter, snapshot)?;
         } else if sys_info.loadperc
             >= config
                 .plugged_in
@@ -619,12 +2557,14 @@ pub fn optimize_powerstate(
                 .loadperc_threshold
                 .unwrap()
         {
-            high_load_setting_ac(&config, &sys_info, cpus, counter);
+            high_load_setting_ac(config, sys_info, counter, snapshot)?;
         } else {
-            low_load_setting_ac(&config, &sys_info, cpus, counter);
+            low_load_setting_ac(config, sys_info, counter, snapshot)?;
         }
     } else {
-        if sys_info.loadavg
+        if idle {
+            low_load_setting_bat(config, sys_info, counter, snapshot)?;
+        } else if sys_info.loadavg
             > config
                 .on_battery
                 .as_ref()
@@ -632,7 +2572,7 @@ pub fn optimize_powerstate(
                 .loadavg_threshold
                 .unwrap()
         {
-            high_load_setting_bat(&config, &sys_info, cpus, counter);
+            high_load_setting_bat(config, sys_info, counter, snapshot)?;
         } else if sys_info.loadperc
             >= config
                 .on_battery
@@ -641,19 +2581,99 @@ pub fn optimize_powerstate(
                 .loadperc_threshold
                 .unwrap()
         {
-            high_load_setting_bat(&config, &sys_info, cpus, counter);
+            high_load_setting_bat(config, sys_info, counter, snapshot)?;
+        } else {
+            low_load_setting_bat(config, sys_info, counter, snapshot)?;
+        }
+    }
+    if uses_device_power(config) {
+        set_device_power(config, sys_info, snapshot);
+    }
+    let throttles = get_configured_throttles(config);
+    if !throttles.is_empty() {
+        cgroup::set_throttles(&throttles, !sys_info.ac_power, snapshot);
+    }
+    if uses_wifi_power_save(config) {
+        if sys_info.ac_power {
+            set_wifi_power_save(
+                config.plugged_in.as_ref().unwrap().wifi_power_save,
+                snapshot,
+            );
+        } else {
+            set_wifi_power_save(
+                config.on_battery.as_ref().unwrap().wifi_power_save,
+                snapshot,
+            );
+        }
+    }
+    let class_epp_cpus =
+        get_core_class_cpus(config, sys_info.ac_power, |class| class.epp.is_some());
+    if sys_info.ac_power {
+        set_epp(
+            config.plugged_in.as_ref().unwrap().epp.as_deref(),
+            &class_epp_cpus,
+            snapshot,
+        );
+    } else {
+        set_epp(
+            config.on_battery.as_ref().unwrap().epp.as_deref(),
+            &class_epp_cpus,
+            snapshot,
+        );
+    }
+    if uses_core_classes(config) {
+        if sys_info.ac_power {
+            let ac = config.plugged_in.as_ref().unwrap();
+            set_core_policies(
+                ac.performance_cores.as_ref(),
+                ac.efficiency_cores.as_ref(),
+                snapshot,
+            )?;
         } else {
-            low_load_setting_bat(&config, &sys_info, cpus, counter);
+            let bat = config.on_battery.as_ref().unwrap();
+            set_core_policies(
+                bat.performance_cores.as_ref(),
+                bat.efficiency_cores.as_ref(),
+                snapshot,
+            )?;
         }
     }
     println!("");
-    match terminalout.flush() {
-        Ok(_) => (),
-        Err(x) => {
-            eprintln!("[{}] Error: {}", "!".red(), x);
-            std::process::exit(1)
+    terminalout.flush()
+}
+
+/*
+    A manual profile ends when it expires or the power source changes. The
+    daemon removes an ended override, so it isn't picked up again after a
+    restart
+*/
+fn get_manual_profile(
+    state: &mut DaemonState,
+    ac_power: bool,
+    remove: bool,
+) -> Option<ProfileOverride> {
+    let manual = profile::read_override()
+        .filter(|manual| !manual.is_expired() && state.expired_override != Some(manual.set_at))?;
+    if state.last_ac_power.is_none_or(|last| last == ac_power) {
+        return Some(manual);
+    }
+    println!(
+        "[{}] Manual profile '{}' ended by power source change",
+        "+".dark_green(),
+        manual.profile
+    );
+    state.expired_override = Some(manual.set_at);
+    if remove {
+        if let Err(x) = profile::remove_override() {
+            eprintln!(
+                "[{}] Warning: Can't remove {}: {}",
+                "!".yellow(),
+                profile::OVERRIDE_PATH,
+                x
+            );
         }
-    };
+    }
+    None
 }
 
 pub fn monitor_state(
@@ -661,11 +2681,43 @@ pub fn monitor_state(
     sys_info: &SystemInfo,
     cpus: i32,
     counter: &mut u32,
+    state: &mut DaemonState,
     terminalout: &mut std::io::Stdout,
 ) {
     println!("{}", ":".repeat(50));
     println!("{} Suggest optimzations {}", ":".repeat(14), ":".repeat(14));
     println!("{}\n", ":".repeat(50));
+    let manual = get_manual_profile(state, sys_info.ac_power, false);
+    state.last_ac_power = Some(sys_info.ac_power);
+    if let Some(tier) = get_battery_tier(config, sys_info.ac_power, sys_info.battery_capacity) {
+        println!(
+            "[{}] Suggesting battery tier '{}' (below {}%)",
+            "+".dark_green(),
+            tier.profile,
+            tier.below
+        );
+    }
+    let (effective_config, profile) = get_effective_config(config, sys_info, manual.as_ref());
+    let config = &effective_config;
+    if let Some(profile) = profile {
+        println!("[{}] Suggesting profile '{}'", "+".dark_green(), profile);
+    }
+    if let Some(target) = get_runtime_target(config, sys_info) {
+        let needed = target.until.saturating_sub(profile::get_unix_time());
+        if let (Some(energy), Some(power)) =
+            (runtime::get_battery_energy(), energy::get_battery_power())
+        {
+            if power > 0.0 {
+                println!(
+                    "[{}] Runtime target: {} left, projected {} at {:.1}W",
+                    "+".dark_green(),
+                    runtime::format_duration(needed),
+                    runtime::format_duration((energy / power * 3600.0) as u64),
+                    power
+                );
+            }
+        }
+    }
     if sys_info.ac_power {
         if sys_info.loadavg > (50.0 * cpus as f32) / 100.0 {
             println!("[{}] High system load", "+".dark_green());
@@ -688,27 +2740,12 @@ pub fn monitor_state(
             if config.plugged_in.as_ref().unwrap().turbo.unwrap() {
                 *counter = *counter + TIME_INCREMENT_PER_RUN;
                 if *counter >= (*config).plugged_in.as_ref().unwrap().turbo_delay.unwrap() {
-                    println!("[{}] Suggesting setting Turbo on", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(true, sys_info);
                 } else {
-                    println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(false, sys_info);
                 }
             } else {
-                println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                if get_turbo(sys_info.turbo_invert) {
-                    println!("[{}] Turbo is currently on", "+".dark_green());
-                } else {
-                    println!("[{}] Turbo is currently off", "+".dark_green());
-                }
+                suggest_turbo(false, sys_info);
             }
         } else if sys_info.loadperc >= 20.0 {
             println!("[{}] High CPU usage", "+".dark_green());
@@ -731,27 +2768,12 @@ pub fn monitor_state(
             if config.plugged_in.as_ref().unwrap().turbo.unwrap() {
                 *counter = *counter + TIME_INCREMENT_PER_RUN;
                 if *counter >= (*config).plugged_in.as_ref().unwrap().turbo_delay.unwrap() {
-                    println!("[{}] Suggesting setting Turbo on", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(true, sys_info);
                 } else {
-                    println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(false, sys_info);
                 }
             } else {
-                println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                if get_turbo(sys_info.turbo_invert) {
-                    println!("[{}] Turbo is currently on", "+".dark_green());
-                } else {
-                    println!("[{}] Turbo is currently off", "+".dark_green());
-                }
+                suggest_turbo(false, sys_info);
             }
         } else {
             println!("[{}] Load optimal", "+".dark_green());
@@ -771,12 +2793,7 @@ pub fn monitor_state(
                 "+".dark_green(),
                 get_governor()
             );
-            println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-            if get_turbo(sys_info.turbo_invert) {
-                println!("[{}] Turbo is currently on", "+".dark_green());
-            } else {
-                println!("[{}] Turbo is currently off", "+".dark_green());
-            }
+            suggest_turbo(false, sys_info);
             *counter = 0;
         }
     } else {
@@ -801,27 +2818,12 @@ pub fn monitor_state(
             if config.on_battery.as_ref().unwrap().turbo.unwrap() {
                 *counter = *counter + TIME_INCREMENT_PER_RUN;
                 if *counter >= (*config).on_battery.as_ref().unwrap().turbo_delay.unwrap() {
-                    println!("[{}] Suggesting setting Turbo on", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(true, sys_info);
                 } else {
-                    println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(false, sys_info);
                 }
             } else {
-                println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                if get_turbo(sys_info.turbo_invert) {
-                    println!("[{}] Turbo is currently on", "+".dark_green());
-                } else {
-                    println!("[{}] Turbo is currently off", "+".dark_green());
-                }
+                suggest_turbo(false, sys_info);
             }
         } else if sys_info.loadperc >= 30.0 {
             println!("[{}] High CPU usage", "+".dark_green());
@@ -839,27 +2841,12 @@ pub fn monitor_state(
             if config.on_battery.as_ref().unwrap().turbo.unwrap() {
                 *counter = *counter + TIME_INCREMENT_PER_RUN;
                 if *counter >= (*config).on_battery.as_ref().unwrap().turbo_delay.unwrap() {
-                    println!("[{}] Suggesting setting Turbo on", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(true, sys_info);
                 } else {
-                    println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                    if get_turbo(sys_info.turbo_invert) {
-                        println!("[{}] Turbo is currently on", "+".dark_green());
-                    } else {
-                        println!("[{}] Turbo is currently off", "+".dark_green());
-                    }
+                    suggest_turbo(false, sys_info);
                 }
             } else {
-                println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-                if get_turbo(sys_info.turbo_invert) {
-                    println!("[{}] Turbo is currently on", "+".dark_green());
-                } else {
-                    println!("[{}] Turbo is currently off", "+".dark_green());
-                }
+                suggest_turbo(false, sys_info);
             }
         } else {
             println!("[{}] Load optimal", "+".dark_green());
@@ -879,94 +2866,358 @@ pub fn monitor_state(
                 "+".dark_green(),
                 get_governor()
             );
-            println!("[{}] Suggesting setting Turbo off", "+".dark_green());
-            if get_turbo(sys_info.turbo_invert) {
-                println!("[{}] Turbo is currently on", "+".dark_green());
-            } else {
-                println!("[{}] Turbo is currently off", "+".dark_green());
-            }
+            suggest_turbo(false, sys_info);
             *counter = 0;
         }
-    }
-    println!("");
-    match terminalout.flush() {
-        Ok(_) => (),
+    }
+    println!("");
+    match terminalout.flush() {
+        Ok(_) => (),
+        Err(x) => {
+            eprintln!("[{}] Error: {}", "!".red(), x);
+            std::process::exit(1)
+        }
+    };
+}
+
+pub fn print_log(num_cpus: i32, terminalout: &mut std::io::Stdout) {
+    let log_path = "/var/log/yablo.log";
+    let file = std::fs::File::open(log_path).unwrap();
+    let rev_lines = RevLines::new(std::io::BufReader::new(file)).unwrap();
+    let mut last_lines: Vec<String> = Vec::new();
+    let num_lines = num_cpus + 25;
+    let mut i = 0;
+
+    for line in rev_lines {
+        if i >= num_lines {
+            break;
+        }
+        last_lines.push(line);
+
+        i += 1;
+    }
+    last_lines.reverse();
+    match terminalout.execute(crossterm::terminal::Clear(
+        crossterm::terminal::ClearType::All,
+    )) {
+        Ok(_) => (),
+        Err(x) => {
+            eprintln!("[{}] Error: {}", "!".red(), x);
+            std::process::exit(1)
+        }
+    };
+
+    for i in last_lines {
+        println!("{}", i);
+    }
+}
+
+pub fn quit_program(poll_time: u64) -> crossterm::Result<()> {
+    crossterm::terminal::enable_raw_mode()?;
+    if poll(std::time::Duration::from_millis(poll_time))? {
+        match read()? {
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('q'),
+                modifiers: NONE,
+            }) => {
+                EXIT_REQUESTED.store(true, Ordering::SeqCst);
+            }
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers: CONTROL,
+            }) => {
+                EXIT_REQUESTED.store(true, Ordering::SeqCst);
+            }
+            _ => (),
+        }
+    }
+    crossterm::terminal::disable_raw_mode()?;
+    Ok(())
+}
+
+/*
+    Without a system bus yablo still works, only the suspend settings and
+    the re-apply after resume are missing
+*/
+pub fn watch_sleep(config: &Config) -> Option<SleepWatcher> {
+    let connection = match zbus::blocking::Connection::system() {
+        Ok(connection) => connection,
+        Err(x) => {
+            eprintln!(
+                "[{}] Warning: Can't connect to the system bus: {}",
+                "!".yellow(),
+                x
+            );
+            return None;
+        }
+    };
+    match sleep::watch_sleep(&connection, config.suspend.is_some()) {
+        Ok(watcher) => Some(watcher),
+        Err(x) => {
+            eprintln!("[{}] Warning: Can't watch for suspend: {}", "!".yellow(), x);
+            None
+        }
+    }
+}
+
+/*
+    Suspend settings are applied here and not in the thread of the bus, so
+    they can't race with a run. Runs pause until the resume, otherwise the
+    next one would undo the settings before the system is asleep
+*/
+pub fn apply_sleep_events(
+    config: &Config,
+    sleep: Option<&SleepWatcher>,
+    turbo_avail: bool,
+    invert: bool,
+    state: &mut DaemonState,
+) {
+    let sleep = match sleep {
+        Some(sleep) => sleep,
+        None => return,
+    };
+    while let Some(event) = sleep.try_recv() {
+        match event {
+            SleepEvent::Suspend(done) => {
+                println!("[{}] Preparing for suspend", "+".dark_green());
+                if let Some(suspend) = config.suspend.as_ref() {
+                    apply_suspend_settings(suspend, turbo_avail, invert);
+                }
+                state.suspended = true;
+                done.send(()).ok();
+            }
+            SleepEvent::Resume => {
+                println!("[{}] Resumed from suspend", "+".dark_green());
+                state.suspended = false;
+                state.resumed = true;
+            }
+        }
+    }
+}
+
+fn apply_suspend_settings(suspend: &SuspendConfig, turbo_avail: bool, invert: bool) {
+    if let Some(governor) = suspend.governor.as_ref() {
+        println!("[{}] Using '{}' governor", "+".dark_green(), governor);
+        if let Err(x) = set_governor(governor, &[]) {
+            eprintln!("[{}] Warning: {}", "!".yellow(), x);
+        }
+    }
+    if let Some(turbo) = suspend.turbo {
+        if turbo_avail {
+            if let Err(x) = set_turbo(turbo, invert) {
+                eprintln!("[{}] Warning: {}", "!".yellow(), x);
+            }
+        }
+    }
+}
+
+pub fn set_profile(config: &Config, name: &str, duration: Option<&str>) {
+    if !config.profiles.contains_key(name) {
+        eprintln!(
+            "[{}] Error: Profile '{}' doesn't exist! Available profiles: {:?}",
+            "!".red(),
+            name,
+            config.profiles.keys().collect::<Vec<&String>>()
+        );
+        std::process::exit(1)
+    }
+    let seconds = match duration {
+        Some(duration) => match profile::parse_duration(duration) {
+            Some(seconds) => Some(seconds),
+            None => {
+                eprintln!(
+                    "[{}] Error: Invalid duration '{}'! Use e.g. 30m, 2h or 1h30m",
+                    "!".red(),
+                    duration
+                );
+                std::process::exit(1)
+            }
+        },
+        None => None,
+    };
+    let set_at = profile::get_unix_time();
+    let manual = ProfileOverride {
+        profile: name.to_string(),
+        set_at,
+        until: seconds.map(|seconds| set_at + seconds),
+    };
+    match profile::write_override(&manual) {
+        Ok(_) => (),
+        Err(x) => {
+            eprintln!("[{}] Error: {}", "!".red(), x);
+            std::process::exit(1)
+        }
+    }
+    match seconds {
+        Some(seconds) => println!(
+            "[{}] Profile '{}' set for {}min or until the power source changes",
+            "+".dark_green(),
+            name,
+            seconds / 60
+        ),
+        None => println!(
+            "[{}] Profile '{}' set until the power source changes",
+            "+".dark_green(),
+            name
+        ),
+    }
+}
+
+pub fn set_runtime_target(config: &Config, duration: &str) {
+    if config.runtime.is_none() {
+        eprintln!("[{}] Error: No [runtime] levels in config file!", "!".red());
+        std::process::exit(1)
+    }
+    let seconds = match profile::parse_duration(duration) {
+        Some(seconds) => seconds,
+        None => {
+            eprintln!(
+                "[{}] Error: Invalid duration '{}'! Use e.g. 30m, 5h or 4h30m",
+                "!".red(),
+                duration
+            );
+            std::process::exit(1)
+        }
+    };
+    let set_at = profile::get_unix_time();
+    let target = RuntimeTarget {
+        set_at,
+        until: set_at + seconds,
+    };
+    match runtime::write_target(&target) {
+        Ok(_) => println!(
+            "[{}] Battery has to last {}",
+            "+".dark_green(),
+            runtime::format_duration(seconds)
+        ),
         Err(x) => {
             eprintln!("[{}] Error: {}", "!".red(), x);
             std::process::exit(1)
         }
-    };
+    }
 }
 
-pub fn print_log(num_cpus: i32, terminalout: &mut std::io::Stdout) {
-    let log_path = "/var/log/yablo.log";
-    let file = std::fs::File::open(log_path).unwrap();
-    let rev_lines = RevLines::new(std::io::BufReader::new(file)).unwrap();
-    let mut last_lines: Vec<String> = Vec::new();
-    let num_lines = num_cpus + 25;
-    let mut i = 0;
-
-    for line in rev_lines {
-        if i >= num_lines {
-            break;
+pub fn clear_runtime_target() {
+    match runtime::remove_target() {
+        Ok(_) => println!("[{}] Runtime target cleared", "+".dark_green()),
+        Err(x) => {
+            eprintln!("[{}] Error: {}", "!".red(), x);
+            std::process::exit(1)
         }
-        last_lines.push(line);
-
-        i += 1;
     }
-    last_lines.reverse();
-    match terminalout.execute(crossterm::terminal::Clear(
-        crossterm::terminal::ClearType::All,
-    )) {
-        Ok(_) => (),
+}
+
+pub fn clear_profile() {
+    match profile::remove_override() {
+        Ok(_) => println!(
+            "[{}] Automatic profile selection restored",
+            "+".dark_green()
+        ),
         Err(x) => {
             eprintln!("[{}] Error: {}", "!".red(), x);
             std::process::exit(1)
         }
-    };
-
-    for i in last_lines {
-        println!("{}", i);
     }
 }
 
-pub fn quit_program(poll_time: u64, terminalout: &mut std::io::Stdout) -> crossterm::Result<()> {
-    crossterm::terminal::enable_raw_mode()?;
-    if poll(std::time::Duration::from_millis(poll_time))? {
-        match read()? {
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('q'),
-                modifiers: NONE,
-            }) => {
-                crossterm::terminal::disable_raw_mode()?;
-                match terminalout.execute(crossterm::terminal::LeaveAlternateScreen) {
-                    Ok(_) => (),
-                    Err(x) => {
-                        eprintln!("[{}] Error: {}", "!".red(), x);
-                        std::process::exit(1)
-                    }
-                }
-                std::process::exit(0)
+/*
+    Makes the energy totals of the daemon available to the other modes
+*/
+pub fn share_energy_stats(energy: &EnergyMeter, state: &mut DaemonState) {
+    match energy.write_stats() {
+        Ok(_) => state.stats_failed = false,
+        Err(x) => {
+            if !state.stats_failed {
+                eprintln!(
+                    "[{}] Warning: Can't write {}: {}",
+                    "!".yellow(),
+                    energy::STATS_PATH,
+                    x
+                );
             }
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('c'),
-                modifiers: CONTROL,
-            }) => {
-                crossterm::terminal::disable_raw_mode()?;
-                match terminalout.execute(crossterm::terminal::LeaveAlternateScreen) {
-                    Ok(_) => (),
-                    Err(x) => {
-                        eprintln!("[{}] Error: {}", "!".red(), x);
-                        std::process::exit(1)
-                    }
-                }
-                std::process::exit(0)
+            state.stats_failed = true;
+        }
+    }
+}
+
+/*
+    The totals of a running daemon, which has been measuring for longer, or
+    the own ones
+*/
+pub fn get_energy_states(energy: &EnergyMeter) -> Vec<EnergyState> {
+    energy::read_stats().unwrap_or_else(|| energy.states.clone())
+}
+
+/*
+    Reported once at startup, afterwards the daemon polls every 3 seconds
+*/
+pub fn open_uevent_socket() -> Option<UeventSocket> {
+    match UeventSocket::open() {
+        Ok(socket) => Some(socket),
+        Err(x) => {
+            eprintln!(
+                "[{}] Warning: Can't subscribe to power supply events: {}. Falling back to polling every 3 seconds, as a service this needs yablo.service.d/power-supply-events.conf.",
+                "!".yellow(),
+                x
+            );
+            None
+        }
+    }
+}
+
+/*
+    Sleeps until the next run, or less if the power source changes or a
+    sleep event comes in. Signals also end the wait, so an exit request
+    isn't delayed
+*/
+pub fn wait_for_next_run(
+    uevents: Option<&UeventSocket>,
+    sleep: Option<&SleepWatcher>,
+    duration: std::time::Duration,
+) {
+    let deadline = std::time::Instant::now() + duration;
+    loop {
+        let mut fds: Vec<PollFd> = uevents
+            .map(|uevents| uevents.fd())
+            .into_iter()
+            .chain(sleep.map(|sleep| sleep.fd()))
+            .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+            .collect();
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        match nix::poll::poll(&mut fds, remaining.as_millis() as i32) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => (),
+        }
+        if let Some(uevents) = uevents {
+            if uevents.read_events() {
+                println!("[{}] Power source changed", "+".dark_green());
+                return;
             }
-            _ => (),
+        }
+        let sleep_event = fds
+            .last()
+            .and_then(|fd| fd.revents())
+            .is_some_and(|revents| revents.contains(PollFlags::POLLIN));
+        if sleep.is_some() && sleep_event {
+            return;
         }
     }
-    crossterm::terminal::disable_raw_mode()?;
-    Ok(())
+}
+
+/*
+    The snapshot is restored on errors as well, so a failed write doesn't
+    leave the system half configured
+*/
+pub fn exit_program(snapshot: &Snapshot, terminalout: &mut std::io::Stdout, code: i32) -> ! {
+    snapshot.restore();
+    match terminalout.execute(crossterm::terminal::LeaveAlternateScreen) {
+        Ok(_) => (),
+        Err(x) => {
+            eprintln!("[{}] Error: {}", "!".red(), x);
+            std::process::exit(1)
+        }
+    }
+    std::process::exit(code)
 }
 
 pub fn restart_daemon() {
@@ -995,15 +3246,13 @@ pub fn restart_daemon() {
 
 */
 
-fn high_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, counter: &mut u32) {
-    if sys_info.battery_capacity
-        > config
-            .on_battery
-            .as_ref()
-            .unwrap()
-            .battery_threshold
-            .unwrap()
-    {
+fn high_load_setting_bat(
+    config: &Config,
+    sys_info: &SystemInfo,
+    counter: &mut u32,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
+    if !is_low_battery(config, sys_info.battery_capacity) {
         println!("[{}] High system load", "+".dark_green());
         println!(
             "[{}] Using '{}' governor",
@@ -1024,20 +3273,54 @@ fn high_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, coun
                 .second_stage_governor
                 .as_ref()
                 .unwrap(),
-            cpus,
-        );
+            &get_core_class_cpus(config, sys_info.ac_power, |class| class.governor.is_some()),
+        )?;
         if config.on_battery.as_ref().unwrap().turbo.unwrap() {
             *counter = *counter + TIME_INCREMENT_PER_RUN;
             if *counter >= (*config).on_battery.as_ref().unwrap().turbo_delay.unwrap() {
-                set_turbo(true, sys_info.turbo_invert);
-                println!("[{}] Turbo activated", "+".dark_green());
+                apply_turbo(true, sys_info)?;
             } else {
-                println!("[{}] Turbo deactivated", "+".dark_green());
+                apply_turbo(false, sys_info)?;
             }
         } else {
-            set_turbo(false, sys_info.turbo_invert);
-            println!("[{}] Turbo deactivated", "+".dark_green());
+            apply_turbo(false, sys_info)?;
         }
+        set_power_limits(
+            config.on_battery.as_ref().unwrap().second_stage_pl1,
+            config.on_battery.as_ref().unwrap().second_stage_pl2,
+            snapshot,
+        )?;
+        set_perf_pct(
+            config
+                .on_battery
+                .as_ref()
+                .unwrap()
+                .second_stage_min_perf_pct,
+            config
+                .on_battery
+                .as_ref()
+                .unwrap()
+                .second_stage_max_perf_pct,
+            snapshot,
+        )?;
+        set_gpu_freqs(
+            config
+                .on_battery
+                .as_ref()
+                .unwrap()
+                .second_stage_gpu_min_freq,
+            config
+                .on_battery
+                .as_ref()
+                .unwrap()
+                .second_stage_gpu_max_freq,
+            config
+                .on_battery
+                .as_ref()
+                .unwrap()
+                .second_stage_gpu_boost_freq,
+            snapshot,
+        );
     } else {
         println!("[{}] High system load", "+".dark_green());
         println!("[{}] Low battery capacity", "!".yellow());
@@ -1052,21 +3335,44 @@ fn high_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, coun
                 .as_ref()
                 .unwrap()
         );
-        set_governor("powersave", cpus);
-        set_turbo(false, sys_info.turbo_invert);
-        println!("[{}] Turbo deactivated", "+".dark_green());
+        set_governor(
+            config
+                .on_battery
+                .as_ref()
+                .unwrap()
+                .low_battery_governor
+                .as_ref()
+                .unwrap(),
+            &get_core_class_cpus(config, sys_info.ac_power, |class| class.governor.is_some()),
+        )?;
+        apply_turbo(false, sys_info)?;
+        set_power_limits(
+            config.on_battery.as_ref().unwrap().pl1,
+            config.on_battery.as_ref().unwrap().pl2,
+            snapshot,
+        )?;
+        set_perf_pct(
+            config.on_battery.as_ref().unwrap().min_perf_pct,
+            config.on_battery.as_ref().unwrap().max_perf_pct,
+            snapshot,
+        )?;
+        set_gpu_freqs(
+            config.on_battery.as_ref().unwrap().gpu_min_freq,
+            config.on_battery.as_ref().unwrap().gpu_max_freq,
+            config.on_battery.as_ref().unwrap().gpu_boost_freq,
+            snapshot,
+        );
     }
+    Ok(())
 }
 
-fn low_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, counter: &mut u32) {
-    if sys_info.battery_capacity
-        > config
-            .on_battery
-            .as_ref()
-            .unwrap()
-            .battery_threshold
-            .unwrap()
-    {
+fn low_load_setting_bat(
+    config: &Config,
+    sys_info: &SystemInfo,
+    counter: &mut u32,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
+    if !is_low_battery(config, sys_info.battery_capacity) {
         println!("[{}] Load optimal", "+".dark_green());
         println!(
             "[{}] Using '{}' governor",
@@ -1079,7 +3385,6 @@ fn low_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, count
                 .as_ref()
                 .unwrap()
         );
-        println!("[{}] Turbo deactivated", "+".dark_green());
         set_governor(
             config
                 .on_battery
@@ -1088,10 +3393,26 @@ fn low_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, count
                 .governor
                 .as_ref()
                 .unwrap(),
-            cpus,
-        );
+            &get_core_class_cpus(config, sys_info.ac_power, |class| class.governor.is_some()),
+        )?;
         *counter = 0;
-        set_turbo(false, sys_info.turbo_invert);
+        apply_turbo(false, sys_info)?;
+        set_power_limits(
+            config.on_battery.as_ref().unwrap().pl1,
+            config.on_battery.as_ref().unwrap().pl2,
+            snapshot,
+        )?;
+        set_perf_pct(
+            config.on_battery.as_ref().unwrap().min_perf_pct,
+            config.on_battery.as_ref().unwrap().max_perf_pct,
+            snapshot,
+        )?;
+        set_gpu_freqs(
+            config.on_battery.as_ref().unwrap().gpu_min_freq,
+            config.on_battery.as_ref().unwrap().gpu_max_freq,
+            config.on_battery.as_ref().unwrap().gpu_boost_freq,
+            snapshot,
+        );
     } else {
         println!("[{}] Load optimal", "+".dark_green());
         println!("[{}] Low battery capacity", "!".yellow());
@@ -1106,23 +3427,44 @@ fn low_load_setting_bat(config: &Config, sys_info: &SystemInfo, cpus: i32, count
                 .as_ref()
                 .unwrap()
         );
-        println!("[{}] Turbo deactivated", "+".dark_green());
         set_governor(
             config
                 .on_battery
                 .as_ref()
                 .unwrap()
-                .governor
+                .low_battery_governor
                 .as_ref()
                 .unwrap(),
-            cpus,
-        );
+            &get_core_class_cpus(config, sys_info.ac_power, |class| class.governor.is_some()),
+        )?;
         *counter = 0;
-        set_turbo(false, sys_info.turbo_invert);
+        apply_turbo(false, sys_info)?;
+        set_power_limits(
+            config.on_battery.as_ref().unwrap().pl1,
+            config.on_battery.as_ref().unwrap().pl2,
+            snapshot,
+        )?;
+        set_perf_pct(
+            config.on_battery.as_ref().unwrap().min_perf_pct,
+            config.on_battery.as_ref().unwrap().max_perf_pct,
+            snapshot,
+        )?;
+        set_gpu_freqs(
+            config.on_battery.as_ref().unwrap().gpu_min_freq,
+            config.on_battery.as_ref().unwrap().gpu_max_freq,
+            config.on_battery.as_ref().unwrap().gpu_boost_freq,
+            snapshot,
+        );
     }
+    Ok(())
 }
 
-fn high_load_setting_ac(config: &Config, sys_info: &SystemInfo, cpus: i32, counter: &mut u32) {
+fn high_load_setting_ac(
+    config: &Config,
+    sys_info: &SystemInfo,
+    counter: &mut u32,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
     println!("[{}] High CPU usage", "+".dark_green());
     println!(
         "[{}] Using '{}' governor",
@@ -1143,23 +3485,63 @@ fn high_load_setting_ac(config: &Config, sys_info: &SystemInfo, cpus: i32, count
             .second_stage_governor
             .as_ref()
             .unwrap(),
-        cpus,
-    );
+        &get_core_class_cpus(config, sys_info.ac_power, |class| class.governor.is_some()),
+    )?;
     if config.plugged_in.as_ref().unwrap().turbo.unwrap() {
         *counter = *counter + TIME_INCREMENT_PER_RUN;
         if *counter >= (*config).plugged_in.as_ref().unwrap().turbo_delay.unwrap() {
-            set_turbo(true, sys_info.turbo_invert);
-            println!("[{}] Turbo activated", "+".dark_green());
+            apply_turbo(true, sys_info)?;
         } else {
-            println!("[{}] Turbo deactivated", "+".dark_green());
+            apply_turbo(false, sys_info)?;
         }
     } else {
-        set_turbo(false, sys_info.turbo_invert);
-        println!("[{}] Turbo deactivated", "+".dark_green());
+        apply_turbo(false, sys_info)?;
     }
+    set_power_limits(
+        config.plugged_in.as_ref().unwrap().second_stage_pl1,
+        config.plugged_in.as_ref().unwrap().second_stage_pl2,
+        snapshot,
+    )?;
+    set_perf_pct(
+        config
+            .plugged_in
+            .as_ref()
+            .unwrap()
+            .second_stage_min_perf_pct,
+        config
+            .plugged_in
+            .as_ref()
+            .unwrap()
+            .second_stage_max_perf_pct,
+        snapshot,
+    )?;
+    set_gpu_freqs(
+        config
+            .plugged_in
+            .as_ref()
+            .unwrap()
+            .second_stage_gpu_min_freq,
+        config
+            .plugged_in
+            .as_ref()
+            .unwrap()
+            .second_stage_gpu_max_freq,
+        config
+            .plugged_in
+            .as_ref()
+            .unwrap()
+            .second_stage_gpu_boost_freq,
+        snapshot,
+    );
+    Ok(())
 }
 
-fn low_load_setting_ac(config: &Config, sys_info: &SystemInfo, cpus: i32, counter: &mut u32) {
+fn low_load_setting_ac(
+    config: &Config,
+    sys_info: &SystemInfo,
+    counter: &mut u32,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
     println!("[{}] Load optimal", "+".dark_green());
     println!(
         "[{}] Using '{}' governor",
@@ -1181,10 +3563,201 @@ fn low_load_setting_ac(config: &Config, sys_info: &SystemInfo, cpus: i32, counte
             .governor
             .as_ref()
             .unwrap(),
-        cpus,
+        &get_core_class_cpus(config, sys_info.ac_power, |class| class.governor.is_some()),
+    )?;
+    apply_turbo(false, sys_info)?;
+    set_power_limits(
+        config.plugged_in.as_ref().unwrap().pl1,
+        config.plugged_in.as_ref().unwrap().pl2,
+        snapshot,
+    )?;
+    set_perf_pct(
+        config.plugged_in.as_ref().unwrap().min_perf_pct,
+        config.plugged_in.as_ref().unwrap().max_perf_pct,
+        snapshot,
+    )?;
+    set_gpu_freqs(
+        config.plugged_in.as_ref().unwrap().gpu_min_freq,
+        config.plugged_in.as_ref().unwrap().gpu_max_freq,
+        config.plugged_in.as_ref().unwrap().gpu_boost_freq,
+        snapshot,
+    );
+    Ok(())
+}
+
+fn set_device_power(config: &Config, sys_info: &SystemInfo, snapshot: &Snapshot) {
+    let devices = if sys_info.ac_power {
+        config.plugged_in.as_ref().unwrap().devices.clone()
+    } else {
+        config.on_battery.as_ref().unwrap().devices.clone()
+    }
+    .unwrap_or_default();
+    devices::set_device_power(
+        &devices,
+        &config.devices.clone().unwrap_or_default(),
+        snapshot,
     );
-    println!("[{}] Turbo deactivated", "+".dark_green());
-    set_turbo(false, sys_info.turbo_invert);
+    if let Some(policy) = devices.pcie_aspm.as_ref() {
+        println!("[{}] PCIe ASPM policy '{}'", "+".dark_green(), policy);
+    }
+    if let Some(enabled) = devices.runtime_pm {
+        if enabled {
+            println!("[{}] PCI runtime PM activated", "+".dark_green());
+        } else {
+            println!("[{}] PCI runtime PM deactivated", "+".dark_green());
+        }
+    }
+    if let Some(enabled) = devices.usb_autosuspend {
+        if enabled {
+            println!("[{}] USB autosuspend activated", "+".dark_green());
+        } else {
+            println!("[{}] USB autosuspend deactivated", "+".dark_green());
+        }
+    }
+    if let Some(policy) = devices.sata_alpm.as_ref() {
+        println!("[{}] SATA link power policy '{}'", "+".dark_green(), policy);
+    }
+}
+
+/*
+    Core class settings are applied after the load helpers, which leave CPUs
+    of a class with its own governor alone. Online CPUs are looked up again
+    since parking may have changed them
+*/
+fn set_core_policies(
+    performance: Option<&CoreClassConfig>,
+    efficiency: Option<&CoreClassConfig>,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
+    let core_types = topology::get_core_types(&get_online_cpus());
+    for (core_type, class) in [
+        (CoreType::Performance, performance),
+        (CoreType::Efficiency, efficiency),
+    ]
+    .iter()
+    {
+        let cpus = topology::get_cpus_of_type(&core_types, *core_type);
+        let class = class.cloned().unwrap_or_default();
+        if let Some(governor) = class.governor.as_ref() {
+            for cpu in cpus.iter() {
+                set_cpu_governor(*cpu, governor)?;
+            }
+            println!(
+                "[{}] Using '{}' governor on {}",
+                "+".dark_green(),
+                governor,
+                core_type.name().to_lowercase()
+            );
+        }
+        if let Some(epp) = class.epp.as_ref() {
+            for cpu in cpus.iter() {
+                set_cpu_epp(*cpu, epp);
+            }
+            println!(
+                "[{}] Using '{}' EPP on {}",
+                "+".dark_green(),
+                epp,
+                core_type.name().to_lowercase()
+            );
+        }
+        for cpu in cpus.iter() {
+            set_cpu_max_freq(*cpu, class.max_freq, snapshot)?;
+        }
+        if let Some(max_freq) = class.max_freq {
+            println!(
+                "[{}] Limiting {} to {}MHz",
+                "+".dark_green(),
+                core_type.name().to_lowercase(),
+                max_freq
+            );
+        }
+    }
+    Ok(())
+}
+
+// runs a load based SMT change is kept, the load changes with the number of CPUs
+const SMT_HOLD_RUNS: u32 = 10;
+
+fn get_smt_off_load(bat: &PowerConfigBat) -> f32 {
+    bat.disable_smt_below_load
+        .unwrap_or(bat.loadperc_threshold.unwrap() / 2.0)
+}
+
+/*
+    SMT siblings are taken offline before the governor is set, so set_governor
+    only touches the CPUs that remain online. At low load SMT goes off below
+    disable_smt_below_load and comes back above the load thresholds, with
+    the last state held in between
+*/
+fn set_smt_state(config: &Config, sys_info: &SystemInfo, hold: &mut u32) -> std::io::Result<()> {
+    let bat = config.on_battery.as_ref().unwrap();
+    let current = sys_info.smt.unwrap_or(true);
+    *hold = hold.saturating_sub(1);
+    let low_battery = match bat.disable_smt_below {
+        Some(threshold) => sys_info.battery_capacity < threshold,
+        None => false,
+    };
+    let new_state = if sys_info.ac_power {
+        true
+    } else if low_battery {
+        false
+    } else if !bat.disable_smt_on_low_load.unwrap_or(false) {
+        true
+    } else if *hold > 0 {
+        current
+    } else if sys_info.loadavg > bat.loadavg_threshold.unwrap()
+        || sys_info.loadperc >= bat.loadperc_threshold.unwrap()
+    {
+        true
+    } else if sys_info.loadperc < get_smt_off_load(bat) {
+        false
+    } else {
+        current
+    };
+    if sys_info.smt != Some(new_state) {
+        set_smt(new_state)?;
+        *hold = SMT_HOLD_RUNS;
+    }
+    if new_state {
+        println!("[{}] SMT activated", "+".dark_green());
+    } else {
+        println!("[{}] SMT deactivated", "+".dark_green());
+    }
+    Ok(())
+}
+
+/*
+    Parking extends the low load, low battery branch of low_load_setting_bat:
+    below battery_threshold cores go offline, at high load, on AC or with
+    enough capacity they come back. CPUs brought back by re-enabling SMT
+    aren't parked any more and are taken offline again
+*/
+fn set_parking_state(
+    config: &Config,
+    sys_info: &SystemInfo,
+    parked_cpus: &mut Vec<i32>,
+) -> std::io::Result<()> {
+    let bat = config.on_battery.as_ref().unwrap();
+    let count = bat.parked_cores.unwrap();
+    if count == 0 {
+        return Ok(());
+    }
+    let online = get_online_cpus();
+    parked_cpus.retain(|cpu| !online.contains(cpu));
+    let high_load = !is_user_idle(config, sys_info)
+        && (sys_info.loadavg > bat.loadavg_threshold.unwrap()
+            || sys_info.loadperc >= bat.loadperc_threshold.unwrap());
+    if !sys_info.ac_power
+        && !high_load
+        && sys_info.battery_capacity <= bat.battery_threshold.unwrap()
+    {
+        park_cpus(count, parked_cpus)?;
+        println!("[{}] Parked CPUs: {:?}", "!".yellow(), parked_cpus);
+    } else if !parked_cpus.is_empty() {
+        unpark_cpus(parked_cpus)?;
+        println!("[{}] All CPUs online", "+".dark_green());
+    }
+    Ok(())
 }
 
 /*
@@ -1209,6 +3782,9 @@ fn default_battery_threshold() -> Option<u8> {
 fn default_low_battery_governor() -> Option<String> {
     Some(String::from("powersave"))
 }
+fn default_parked_cores() -> Option<u32> {
+    Some(0)
+}
 
 fn default_loadperc_threshold_plugged_in() -> Option<f32> {
     Some(20.0)
@@ -1227,3 +3803,63 @@ fn default_loadavg_threshold_on_battery() -> Option<f32> {
     let num_cores = num_cpus::get() as i32;
     Some((75.0 * num_cores as f32) / 100.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier_config(tiers: Option<Vec<(u8, &str)>>, battery_threshold: u8) -> Config {
+        Config {
+            plugged_in: Some(PowerConfigAC::default()),
+            on_battery: Some(PowerConfigBat {
+                battery_threshold: Some(battery_threshold),
+                tiers: tiers.map(|tiers| {
+                    tiers
+                        .into_iter()
+                        .map(|(below, profile)| BatteryTier {
+                            below,
+                            profile: profile.to_string(),
+                        })
+                        .collect()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn battery_tier_only_on_battery() {
+        let config = tier_config(Some(vec![(50, "half"), (20, "low")]), 0);
+        assert_eq!(
+            get_battery_tier(&config, true, 10).map(|tier| tier.below),
+            None
+        );
+        assert_eq!(
+            get_battery_tier(&config, false, 60).map(|tier| tier.below),
+            None
+        );
+        assert_eq!(
+            get_battery_tier(&config, false, 40).map(|tier| tier.below),
+            Some(50)
+        );
+        assert_eq!(
+            get_battery_tier(&config, false, 10).map(|tier| tier.below),
+            Some(20)
+        );
+        let config = tier_config(None, 0);
+        assert!(get_battery_tier(&config, false, 10).is_none());
+    }
+
+    #[test]
+    fn tiers_replace_the_threshold() {
+        let config = tier_config(None, 20);
+        assert!(is_low_battery(&config, 20));
+        assert!(!is_low_battery(&config, 21));
+        let config = tier_config(Some(vec![(30, "low")]), 20);
+        assert!(!is_low_battery(&config, 10));
+        // an empty list is no tiers
+        let config = tier_config(Some(Vec::new()), 20);
+        assert!(is_low_battery(&config, 10));
+    }
+}
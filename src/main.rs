@@ -9,8 +9,11 @@ use crossterm::style::Stylize;
 use crossterm::ExecutableCommand;
 use systemstat::{Platform, System};
 
+use yablo as lib;
+use yablo::cgroup::CgroupMeter;
+use yablo::energy::{self, EnergyMeter};
+
 mod cli;
-mod lib;
 
 fn main() {
 
@@ -29,23 +32,59 @@ fn main() {
         lib::check_config_existence();
         let config = lib::get_config();
         lib::check_config_errors(&config);
-        let num_cores = num_cpus::get() as i32;
         let sys = System::new();
         let (turbo_available, invert_turbo) = lib::check_turbo_availability();
         lib::check_daemon();
         lib::check_log();
+        let snapshot = lib::take_snapshot(&config);
+        lib::register_exit_handler();
         let mut daemon_count = 0;
+        let mut state = lib::DaemonState::default();
+        let mut energy = EnergyMeter::default();
+        let mut cgroup_load = CgroupMeter::default();
+        let uevents = lib::open_uevent_socket();
+        let sleep = lib::watch_sleep(&config);
         loop {
-            let sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo, num_cores);
-            lib::print_info(&sys_info, &mut stdout);
-            lib::optimize_powerstate(
+            // no runs between a suspend and the resume
+            if !state.suspended {
+                let mut sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo);
+                lib::update_schedule(&config, &mut sys_info);
+                lib::update_dock(&config, &mut sys_info);
+                lib::update_idle(&config, &mut sys_info);
+                lib::update_load(&config, &mut cgroup_load, &mut sys_info);
+                lib::print_info(&sys_info, &energy.states, &mut stdout);
+                if let Err(x) = lib::optimize_powerstate(
+                    &config,
+                    &sys_info,
+                    &mut daemon_count,
+                    &mut state,
+                    &snapshot,
+                    &mut stdout,
+                ) {
+                    eprintln!("[{}] Error: {}. exit.", "!".red(), x);
+                    energy::remove_stats().ok();
+                    lib::exit_program(&snapshot, &mut stdout, 1);
+                }
+                energy.sample(lib::get_power_state(&sys_info), !sys_info.ac_power);
+                lib::share_energy_stats(&energy, &mut state);
+            }
+            lib::wait_for_next_run(
+                uevents.as_ref(),
+                sleep.as_ref(),
+                std::time::Duration::from_secs(3),
+            );
+            if lib::exit_requested() {
+                // the totals of a stopped daemon would look current in --monitor
+                energy::remove_stats().ok();
+                lib::exit_program(&snapshot, &mut stdout, 0);
+            }
+            lib::apply_sleep_events(
                 &config,
-                &sys_info,
-                num_cores,
-                &mut daemon_count,
-                &mut stdout,
+                sleep.as_ref(),
+                turbo_available,
+                invert_turbo,
+                &mut state,
             );
-            std::thread::sleep(std::time::Duration::from_secs(3));
         }
     } else if matches.is_present("monitor") {
         let mut stdout = std::io::stdout();
@@ -63,23 +102,34 @@ fn main() {
         let sys = System::new();
         let (turbo_available, invert_turbo) = lib::check_turbo_availability();
         let mut monitor_count = 0;
+        let mut state = lib::DaemonState::default();
+        let mut energy = EnergyMeter::default();
+        let mut cgroup_load = CgroupMeter::default();
         loop {
-            let sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo, num_cores);
-            lib::print_info(&sys_info, &mut stdout);
+            let mut sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo);
+            lib::update_schedule(&config, &mut sys_info);
+            lib::update_dock(&config, &mut sys_info);
+            lib::update_load(&config, &mut cgroup_load, &mut sys_info);
+            energy.sample(lib::get_power_state(&sys_info), !sys_info.ac_power);
+            lib::print_info(&sys_info, &lib::get_energy_states(&energy), &mut stdout);
             lib::monitor_state(
                 &config,
                 &sys_info,
                 num_cores,
                 &mut monitor_count,
+                &mut state,
                 &mut stdout,
             );
-            match lib::quit_program(3000, &mut stdout) {
+            match lib::quit_program(3000) {
                 Ok(_) => (),
                 Err(err) => {
                     eprintln!("Error: {}", err);
                     std::process::exit(1)
                 }
             };
+            if lib::exit_requested() {
+                lib::exit_program(&Default::default(), &mut stdout, 0);
+            }
         }
     } else if matches.is_present("live") {
         lib::check_root();
@@ -94,23 +144,44 @@ fn main() {
         lib::check_config_existence();
         let config = lib::get_config();
         lib::check_config_errors(&config);
-        let num_cores = num_cpus::get() as i32;
         let sys = System::new();
         let (turbo_available, invert_turbo) = lib::check_turbo_availability();
         let mut live_count = 0;
+        let mut state = lib::DaemonState::default();
+        let mut energy = EnergyMeter::default();
+        let mut cgroup_load = CgroupMeter::default();
         lib::check_daemon();
+        let snapshot = lib::take_snapshot(&config);
+        lib::register_exit_handler();
         loop {
-            let sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo, num_cores);
-            lib::print_info(&sys_info, &mut stdout);
-            lib::optimize_powerstate(&config, &sys_info, num_cores, &mut live_count, &mut stdout);
+            let mut sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo);
+            lib::update_schedule(&config, &mut sys_info);
+            lib::update_dock(&config, &mut sys_info);
+            lib::update_load(&config, &mut cgroup_load, &mut sys_info);
+            lib::print_info(&sys_info, &energy.states, &mut stdout);
+            if let Err(x) = lib::optimize_powerstate(
+                &config,
+                &sys_info,
+                &mut live_count,
+                &mut state,
+                &snapshot,
+                &mut stdout,
+            ) {
+                eprintln!("[{}] Error: {}. exit.", "!".red(), x);
+                lib::exit_program(&snapshot, &mut stdout, 1);
+            }
+            energy.sample(lib::get_power_state(&sys_info), !sys_info.ac_power);
             println!("{}", ":".repeat(50));
-            match lib::quit_program(3000, &mut stdout) {
+            match lib::quit_program(3000) {
                 Ok(_) => (),
                 Err(err) => {
                     eprintln!("Error: {}", err);
                     std::process::exit(1)
                 }
             };
+            if lib::exit_requested() {
+                lib::exit_program(&snapshot, &mut stdout, 0);
+            }
         }
     } else if matches.is_present("log") {
         let num_cores = num_cpus::get() as i32;
@@ -124,13 +195,16 @@ fn main() {
         };
         loop {
             lib::print_log(num_cores, &mut stdout);
-            match lib::quit_program(500, &mut stdout) {
+            match lib::quit_program(500) {
                 Ok(_) => (),
                 Err(err) => {
                     eprintln!("Error: {}", err);
                     std::process::exit(1)
                 }
             };
+            if lib::exit_requested() {
+                lib::exit_program(&Default::default(), &mut stdout, 0);
+            }
         }
     } else if matches.is_present("debug") {
         let mut stdout = std::io::stdout();
@@ -143,17 +217,21 @@ fn main() {
         };
         let sys = System::new();
         let (turbo_available, invert_turbo) = lib::check_turbo_availability();
-        let num_cores = num_cpus::get() as i32;
+        let mut energy = EnergyMeter::default();
         loop {
-            let sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo, num_cores);
-            lib::print_info(&sys_info, &mut stdout);
-            match lib::quit_program(500, &mut stdout) {
+            let sys_info = lib::get_sys_info(&sys, turbo_available, invert_turbo);
+            energy.sample(lib::get_power_state(&sys_info), !sys_info.ac_power);
+            lib::print_info(&sys_info, &lib::get_energy_states(&energy), &mut stdout);
+            match lib::quit_program(500) {
                 Ok(_) => (),
                 Err(err) => {
                     eprintln!("Error: {}", err);
                     std::process::exit(1)
                 }
             };
+            if lib::exit_requested() {
+                lib::exit_program(&Default::default(), &mut stdout, 0);
+            }
         }
     } else if matches.is_present("update") {
         lib::check_root();
@@ -162,6 +240,28 @@ fn main() {
             "[{}] Successfully restarted daemon. New config loaded.",
             "+".dark_green()
         );
+    } else if let Some(profile) = matches.subcommand_matches("profile") {
+        lib::check_root();
+        if let Some(set) = profile.subcommand_matches("set") {
+            lib::check_config_existence();
+            let config = lib::get_config();
+            lib::set_profile(&config, set.value_of("name").unwrap(), set.value_of("for"));
+        } else if profile.subcommand_matches("clear").is_some() {
+            lib::clear_profile();
+        } else {
+            println!("Type 'yablo profile --help' to get available options");
+        }
+    } else if let Some(runtime) = matches.subcommand_matches("runtime") {
+        lib::check_root();
+        if let Some(set) = runtime.subcommand_matches("set") {
+            lib::check_config_existence();
+            let config = lib::get_config();
+            lib::set_runtime_target(&config, set.value_of("duration").unwrap());
+        } else if runtime.subcommand_matches("clear").is_some() {
+            lib::clear_runtime_target();
+        } else {
+            println!("Type 'yablo runtime --help' to get available options");
+        }
     } else {
         println!("Type 'yablo --help' to get available options");
     }
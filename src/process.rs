@@ -0,0 +1,75 @@
+use crate::sysfs::read_trimmed;
+
+/*
+    Running processes from /proc for process rules
+*/
+
+const PROC_PATH: &str = "/proc";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub name: String,
+    pub cmdline: String,
+    pub cgroup: String,
+}
+
+/*
+    Arguments in /proc/<pid>/cmdline are separated by null bytes
+*/
+fn read_cmdline(pid: i32) -> String {
+    match std::fs::read(format!("{}/{}/cmdline", PROC_PATH, pid)) {
+        Ok(cmdline) => String::from_utf8_lossy(&cmdline)
+            .split('\0')
+            .filter(|arg| !arg.is_empty())
+            .collect::<Vec<&str>>()
+            .join(" "),
+        Err(_) => String::new(),
+    }
+}
+
+/*
+    cgroup v2 has a single "0::<path>" line
+*/
+fn read_cgroup(pid: i32) -> String {
+    read_trimmed(&format!("{}/{}/cgroup", PROC_PATH, pid))
+        .and_then(|cgroup| {
+            cgroup
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(|path| path.to_string()))
+        })
+        .unwrap_or_default()
+}
+
+/*
+    Command lines and cgroups are only read if a rule needs them
+*/
+pub fn get_processes(with_cmdline: bool, with_cgroup: bool) -> Vec<ProcessInfo> {
+    let entries = match std::fs::read_dir(PROC_PATH) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_string_lossy().parse::<i32>().ok())
+        .filter_map(|pid| {
+            let name = read_trimmed(&format!("{}/{}/comm", PROC_PATH, pid))?;
+            let cmdline = if with_cmdline {
+                read_cmdline(pid)
+            } else {
+                String::new()
+            };
+            let cgroup = if with_cgroup {
+                read_cgroup(pid)
+            } else {
+                String::new()
+            };
+            Some(ProcessInfo {
+                pid,
+                name,
+                cmdline,
+                cgroup,
+            })
+        })
+        .collect()
+}
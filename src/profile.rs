@@ -0,0 +1,458 @@
+use crate::epb::EnergyPerfBias;
+use crate::process::ProcessInfo;
+use crate::{Config, CoreClassConfig};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+/*
+    Named profiles that override the settings of the current power source,
+    selected by rules on the system state
+*/
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Profile {
+    pub governor: Option<String>,
+    pub second_stage_governor: Option<String>,
+    pub turbo: Option<bool>,
+    pub turbo_delay: Option<u32>,
+    pub loadperc_threshold: Option<f32>,
+    pub loadavg_threshold: Option<f32>,
+    pub pl1: Option<f32>,
+    pub pl2: Option<f32>,
+    pub second_stage_pl1: Option<f32>,
+    pub second_stage_pl2: Option<f32>,
+    pub min_perf_pct: Option<u8>,
+    pub max_perf_pct: Option<u8>,
+    pub second_stage_min_perf_pct: Option<u8>,
+    pub second_stage_max_perf_pct: Option<u8>,
+    pub epb: Option<EnergyPerfBias>,
+    pub epp: Option<String>,
+    pub gpu_min_freq: Option<u32>,
+    pub gpu_max_freq: Option<u32>,
+    pub gpu_boost_freq: Option<u32>,
+    pub second_stage_gpu_min_freq: Option<u32>,
+    pub second_stage_gpu_max_freq: Option<u32>,
+    pub second_stage_gpu_boost_freq: Option<u32>,
+    pub wifi_power_save: Option<bool>,
+    // only applied on battery
+    pub battery_threshold: Option<u8>,
+    pub low_battery_governor: Option<String>,
+    pub performance_cores: Option<CoreClassConfig>,
+    pub efficiency_cores: Option<CoreClassConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Rule {
+    pub profile: String,
+    pub ac_power: Option<bool>,
+    pub lid: Option<String>,
+    pub external_display: Option<bool>,
+    pub docked: Option<bool>,
+    // process names as in /proc/<pid>/comm, truncated to 15 characters
+    #[serde(default)]
+    pub processes: Vec<String>,
+    // regex on the command line, compiled when the config is read
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub cmdline: Option<Regex>,
+    // part of the cgroup v2 path, e.g. "app-steam"
+    pub cgroup: Option<String>,
+}
+
+/*
+    An invalid regex is reported like any other error in the config file
+*/
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let pattern: String = serde::Deserialize::deserialize(deserializer)?;
+    match Regex::new(&pattern) {
+        Ok(regex) => Ok(Some(regex)),
+        Err(x) => Err(serde::de::Error::custom(format!(
+            "invalid cmdline regex '{}': {}",
+            pattern, x
+        ))),
+    }
+}
+
+/*
+    Battery capacity tier on battery, applies below `below` percent
+*/
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BatteryTier {
+    pub below: u8,
+    pub profile: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DockConfig {
+    #[serde(default)]
+    pub usb_ids: Vec<String>,
+}
+
+/*
+    Manual profile set with `yablo profile set`, valid until it expires or
+    the power source changes
+*/
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ProfileOverride {
+    pub profile: String,
+    pub set_at: u64,
+    pub until: Option<u64>,
+}
+
+pub const OVERRIDE_PATH: &str = "/run/yablo/profile.toml";
+
+pub const LID_STATES: [&str; 2] = ["open", "closed"];
+
+/*
+    Everything rules can match on
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PolicyInputs {
+    pub ac_power: bool,
+    pub lid_closed: Option<bool>,
+    pub external_display: bool,
+    pub docked: bool,
+    // only scanned if a rule matches on processes
+    pub processes: Vec<ProcessInfo>,
+}
+
+impl Rule {
+    pub fn uses_processes(&self) -> bool {
+        !self.processes.is_empty() || self.cmdline.is_some() || self.cgroup.is_some()
+    }
+
+    /*
+        A single process has to match the name, command line and cgroup
+        conditions of the rule. Kernel threads have an empty command line,
+        which a cmdline regex like ".*" must not match
+    */
+    pub fn find_process<'a>(&self, processes: &'a [ProcessInfo]) -> Option<&'a ProcessInfo> {
+        processes.iter().find(|process| {
+            (self.processes.is_empty() || self.processes.contains(&process.name))
+                && self.cmdline.as_ref().is_none_or(|cmdline| {
+                    !process.cmdline.is_empty() && cmdline.is_match(&process.cmdline)
+                })
+                && self
+                    .cgroup
+                    .as_deref()
+                    .is_none_or(|cgroup| process.cgroup.contains(cgroup))
+        })
+    }
+
+    pub fn matches(&self, inputs: &PolicyInputs) -> bool {
+        let lid = match self.lid.as_deref() {
+            Some(lid) => inputs.lid_closed == Some(lid == "closed"),
+            None => true,
+        };
+        lid && self.ac_power.is_none_or(|ac| ac == inputs.ac_power)
+            && self
+                .external_display
+                .is_none_or(|external| external == inputs.external_display)
+            && self.docked.is_none_or(|docked| docked == inputs.docked)
+            && (!self.uses_processes() || self.find_process(&inputs.processes).is_some())
+    }
+
+    /*
+        The conditions of a matching rule, e.g. "cargo running (pid 1234), AC power"
+    */
+    pub fn reason(&self, inputs: &PolicyInputs) -> String {
+        let mut reasons = Vec::new();
+        if self.uses_processes() {
+            if let Some(process) = self.find_process(&inputs.processes) {
+                reasons.push(format!("{} running (pid {})", process.name, process.pid));
+            }
+        }
+        if let Some(ac) = self.ac_power {
+            reasons.push(if ac { "AC power" } else { "battery" }.to_string());
+        }
+        if let Some(lid) = self.lid.as_ref() {
+            reasons.push(format!("lid {}", lid));
+        }
+        if let Some(external) = self.external_display {
+            reasons.push(
+                if external {
+                    "external display"
+                } else {
+                    "no external display"
+                }
+                .to_string(),
+            );
+        }
+        if let Some(docked) = self.docked {
+            reasons.push(if docked { "docked" } else { "undocked" }.to_string());
+        }
+        reasons.join(", ")
+    }
+}
+
+/*
+    The first matching rule wins
+*/
+pub fn select_profile<'a>(rules: &'a [Rule], inputs: &PolicyInputs) -> Option<&'a Rule> {
+    rules.iter().find(|rule| rule.matches(inputs))
+}
+
+/*
+    The tier with the lowest bound above the capacity wins, so the order in
+    the config file doesn't matter
+*/
+pub fn select_tier(tiers: &[BatteryTier], capacity: u8) -> Option<&BatteryTier> {
+    tiers
+        .iter()
+        .filter(|tier| capacity < tier.below)
+        .min_by_key(|tier| tier.below)
+}
+
+macro_rules! overlay {
+    ($target:expr, $profile:expr, $($field:ident),*) => {
+        $(
+            if let Some(value) = $profile.$field.clone() {
+                $target.$field = Some(value);
+            }
+        )*
+    };
+}
+
+/*
+    Returns a copy of the config with the profile applied to the settings of
+    the current power source
+*/
+pub fn apply_profile(config: &Config, profile: &Profile, ac_power: bool) -> Config {
+    let mut config = config.clone();
+    if ac_power {
+        let ac = config.plugged_in.as_mut().unwrap();
+        overlay!(
+            ac,
+            profile,
+            governor,
+            second_stage_governor,
+            turbo,
+            turbo_delay,
+            loadperc_threshold,
+            loadavg_threshold,
+            pl1,
+            pl2,
+            second_stage_pl1,
+            second_stage_pl2,
+            min_perf_pct,
+            max_perf_pct,
+            second_stage_min_perf_pct,
+            second_stage_max_perf_pct,
+            epb,
+            epp,
+            gpu_min_freq,
+            gpu_max_freq,
+            gpu_boost_freq,
+            second_stage_gpu_min_freq,
+            second_stage_gpu_max_freq,
+            second_stage_gpu_boost_freq,
+            wifi_power_save
+        );
+        overlay_core_class(
+            &mut ac.performance_cores,
+            profile.performance_cores.as_ref(),
+        );
+        overlay_core_class(&mut ac.efficiency_cores, profile.efficiency_cores.as_ref());
+    } else {
+        let bat = config.on_battery.as_mut().unwrap();
+        overlay!(
+            bat,
+            profile,
+            governor,
+            second_stage_governor,
+            turbo,
+            turbo_delay,
+            loadperc_threshold,
+            loadavg_threshold,
+            pl1,
+            pl2,
+            second_stage_pl1,
+            second_stage_pl2,
+            min_perf_pct,
+            max_perf_pct,
+            second_stage_min_perf_pct,
+            second_stage_max_perf_pct,
+            epb,
+            epp,
+            gpu_min_freq,
+            gpu_max_freq,
+            gpu_boost_freq,
+            second_stage_gpu_min_freq,
+            second_stage_gpu_max_freq,
+            second_stage_gpu_boost_freq,
+            wifi_power_save,
+            battery_threshold,
+            low_battery_governor
+        );
+        overlay_core_class(
+            &mut bat.performance_cores,
+            profile.performance_cores.as_ref(),
+        );
+        overlay_core_class(&mut bat.efficiency_cores, profile.efficiency_cores.as_ref());
+    }
+    config
+}
+
+/*
+    A core class of a profile only replaces the settings it has, e.g. a
+    max_freq keeps the governor of the power source
+*/
+fn overlay_core_class(target: &mut Option<CoreClassConfig>, class: Option<&CoreClassConfig>) {
+    if let Some(class) = class {
+        let target = target.get_or_insert_with(CoreClassConfig::default);
+        overlay!(target, class, governor, epp, max_freq);
+    }
+}
+
+pub fn get_unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+impl ProfileOverride {
+    pub fn is_expired(&self) -> bool {
+        self.until.is_some_and(|until| get_unix_time() >= until)
+    }
+}
+
+pub fn read_override() -> Option<ProfileOverride> {
+    toml::from_str(&std::fs::read_to_string(OVERRIDE_PATH).ok()?).ok()
+}
+
+pub fn write_override(manual: &ProfileOverride) -> std::io::Result<()> {
+    let content = toml::to_string(manual)
+        .map_err(|x| std::io::Error::new(std::io::ErrorKind::InvalidData, x))?;
+    if let Some(dir) = std::path::Path::new(OVERRIDE_PATH).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(OVERRIDE_PATH, content)
+}
+
+pub fn remove_override() -> std::io::Result<()> {
+    match std::fs::remove_file(OVERRIDE_PATH) {
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/*
+    Durations like "90s", "30m", "2h" or "1h30m", in seconds, every number
+    needs a unit and zero or an overflow is no duration
+*/
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in duration.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let value = number.parse::<u64>().ok()?;
+        number.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return None,
+        };
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))?;
+    }
+    if !number.is_empty() || seconds == 0 {
+        return None;
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(bounds: &[(u8, &str)]) -> Vec<BatteryTier> {
+        bounds
+            .iter()
+            .map(|(below, profile)| BatteryTier {
+                below: *below,
+                profile: profile.to_string(),
+            })
+            .collect()
+    }
+
+    fn selected(tiers: &[BatteryTier], capacity: u8) -> Option<&str> {
+        select_tier(tiers, capacity).map(|tier| tier.profile.as_str())
+    }
+
+    #[test]
+    fn tier_bounds_are_exclusive() {
+        let tiers = tiers(&[(60, "medium"), (30, "low"), (15, "critical")]);
+        assert_eq!(selected(&tiers, 60), None);
+        assert_eq!(selected(&tiers, 59), Some("medium"));
+        assert_eq!(selected(&tiers, 30), Some("medium"));
+        assert_eq!(selected(&tiers, 29), Some("low"));
+        assert_eq!(selected(&tiers, 15), Some("low"));
+        assert_eq!(selected(&tiers, 14), Some("critical"));
+        assert_eq!(selected(&tiers, 0), Some("critical"));
+    }
+
+    #[test]
+    fn tier_order_does_not_matter() {
+        let tiers = tiers(&[(15, "critical"), (60, "medium"), (30, "low")]);
+        assert_eq!(selected(&tiers, 50), Some("medium"));
+        assert_eq!(selected(&tiers, 20), Some("low"));
+        assert_eq!(selected(&tiers, 10), Some("critical"));
+    }
+
+    #[test]
+    fn full_battery() {
+        assert_eq!(selected(&tiers(&[(60, "medium")]), 100), None);
+        // below = 100 covers everything but a full battery
+        let tiers = tiers(&[(100, "battery"), (30, "low")]);
+        assert_eq!(selected(&tiers, 100), None);
+        assert_eq!(selected(&tiers, 99), Some("battery"));
+        assert_eq!(selected(&tiers, 29), Some("low"));
+        assert_eq!(selected(&[], 50), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration(" 90s "), Some(90));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("5"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("213503982334602d"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+    }
+
+    #[test]
+    fn empty_cmdline_only_fails_the_regex() {
+        let kthread = ProcessInfo {
+            pid: 2,
+            name: "kthreadd".to_string(),
+            ..Default::default()
+        };
+        let processes = vec![kthread];
+        let by_name = Rule {
+            processes: vec!["kthreadd".to_string()],
+            ..Default::default()
+        };
+        assert!(by_name.find_process(&processes).is_some());
+        let by_cmdline = Rule {
+            cmdline: Some(Regex::new(".*").unwrap()),
+            ..Default::default()
+        };
+        assert!(by_cmdline.find_process(&processes).is_none());
+    }
+}
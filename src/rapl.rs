@@ -0,0 +1,109 @@
+use crate::snapshot::Snapshot;
+use crate::sysfs::{self, read_value};
+
+/*
+    Package power limits (PL1/PL2) via the intel-rapl powercap interface
+*/
+
+const POWERCAP_PATH: &str = "/sys/class/powercap";
+
+pub const LONG_TERM: &str = "long_term";
+pub const SHORT_TERM: &str = "short_term";
+
+pub fn get_package_zones() -> Vec<String> {
+    sysfs::list_dir(POWERCAP_PATH)
+        .into_iter()
+        .filter(|name| name.starts_with("intel-rapl:") && name.matches(':').count() == 1)
+        .map(|name| format!("{}/{}", POWERCAP_PATH, name))
+        .collect()
+}
+
+pub fn check_rapl_availability() -> bool {
+    !get_package_zones().is_empty()
+}
+
+fn get_constraint(zone: &str, constraint: &str) -> Option<String> {
+    (0..3).map(|k| format!("{}/constraint_{}", zone, k)).find(
+        |prefix| match std::fs::read_to_string(format!("{}_name", prefix)) {
+            Ok(name) => name.trim() == constraint,
+            Err(_) => false,
+        },
+    )
+}
+
+pub fn get_power_limit(zone: &str, constraint: &str) -> Option<f32> {
+    let prefix = get_constraint(zone, constraint)?;
+    read_value::<u64>(&format!("{}_power_limit_uw", prefix)).map(|uw| uw as f32 / 1e6)
+}
+
+pub fn get_power_limits() -> Vec<(Option<f32>, Option<f32>)> {
+    get_package_zones()
+        .iter()
+        .map(|zone| {
+            (
+                get_power_limit(zone, LONG_TERM),
+                get_power_limit(zone, SHORT_TERM),
+            )
+        })
+        .collect()
+}
+
+pub fn check_power_limit(constraint: &str, watts: f32) -> Result<(), String> {
+    if watts <= 0.0 {
+        return Err(format!("power limit {}W has to be positive", watts));
+    }
+    for zone in get_package_zones() {
+        let prefix = match get_constraint(&zone, constraint) {
+            Some(prefix) => prefix,
+            None => return Err(format!("'{}' has no {} constraint", zone, constraint)),
+        };
+        // a max_power_uw of 0 means the firmware doesn't report a maximum
+        if let Some(max) = read_value::<u64>(&format!("{}_max_power_uw", prefix)) {
+            if max > 0 && (watts * 1e6) as u64 > max {
+                return Err(format!(
+                    "power limit {}W exceeds the maximum of {}W for '{}'",
+                    watts,
+                    max as f32 / 1e6,
+                    zone
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn save_power_limits(snapshot: &mut Snapshot) {
+    for zone in get_package_zones() {
+        for constraint in [LONG_TERM, SHORT_TERM].iter() {
+            if let Some(prefix) = get_constraint(&zone, constraint) {
+                snapshot.save(&format!("{}_power_limit_uw", prefix));
+            }
+        }
+    }
+}
+
+/*
+    Without a configured limit the value from the startup snapshot is written back,
+    so a battery cap doesn't stick after plugging in
+*/
+pub fn set_power_limit(
+    constraint: &str,
+    watts: Option<f32>,
+    snapshot: &Snapshot,
+) -> std::io::Result<()> {
+    for zone in get_package_zones() {
+        let path = match get_constraint(&zone, constraint) {
+            Some(prefix) => format!("{}_power_limit_uw", prefix),
+            None => continue,
+        };
+        let output = match watts {
+            Some(watts) => ((watts * 1e6) as u64).to_string(),
+            None => match snapshot.original(&path) {
+                Some(value) => value.to_string(),
+                None => continue,
+            },
+        };
+        sysfs::write(&path, &output)?;
+    }
+    Ok(())
+}
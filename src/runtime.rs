@@ -0,0 +1,231 @@
+use crate::energy;
+use crate::sysfs::read_value;
+use serde_derive::{Deserialize, Serialize};
+
+/*
+    Target runtime on battery: profiles from a ladder are added one by one
+    while the projected runtime falls short of the target, and removed again
+    when there is slack
+*/
+
+pub const TARGET_PATH: &str = "/run/yablo/runtime.toml";
+
+// runs between level changes, so the power draw can settle
+const HOLD_RUNS: u32 = 10;
+// weight of a new power reading in the moving average
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RuntimeConfig {
+    // profiles applied on top of each other, mildest first
+    #[serde(default)]
+    pub levels: Vec<String>,
+    // projected runtime above the target in percent before loosening
+    #[serde(default = "default_slack")]
+    pub slack: Option<u8>,
+}
+
+/*
+    Set with `yablo runtime set`, the battery has to last until `until`
+*/
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RuntimeTarget {
+    pub set_at: u64,
+    pub until: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeState {
+    pub level: usize,
+    // smoothed discharge power in watts
+    pub power: Option<f64>,
+    // runs since the last level change
+    held: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Projection {
+    pub needed: u64,
+    pub projected: u64,
+    pub power: f64,
+}
+
+pub fn read_target() -> Option<RuntimeTarget> {
+    toml::from_str(&std::fs::read_to_string(TARGET_PATH).ok()?).ok()
+}
+
+pub fn write_target(target: &RuntimeTarget) -> std::io::Result<()> {
+    let content = toml::to_string(target)
+        .map_err(|x| std::io::Error::new(std::io::ErrorKind::InvalidData, x))?;
+    if let Some(dir) = std::path::Path::new(TARGET_PATH).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(TARGET_PATH, content)
+}
+
+pub fn remove_target() -> std::io::Result<()> {
+    match std::fs::remove_file(TARGET_PATH) {
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/*
+    Remaining energy in watt hours summed over all batteries, either from
+    energy_now or from charge_now and voltage_now
+*/
+pub fn get_battery_energy() -> Option<f64> {
+    let mut total: Option<f64> = None;
+    for supply in energy::get_batteries() {
+        let energy = match read_value::<u64>(&format!("{}/energy_now", supply)) {
+            Some(uwh) => Some(uwh as f64 / 1e6),
+            None => match (
+                read_value::<u64>(&format!("{}/charge_now", supply)),
+                read_value::<u64>(&format!("{}/voltage_now", supply)),
+            ) {
+                (Some(uah), Some(uv)) => Some(uah as f64 * uv as f64 / 1e12),
+                _ => None,
+            },
+        };
+        if let Some(wh) = energy {
+            total = Some(total.unwrap_or(0.0) + wh);
+        }
+    }
+    total
+}
+
+/*
+    e.g. 2h05m
+*/
+pub fn format_duration(seconds: u64) -> String {
+    format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+}
+
+impl RuntimeTarget {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.until
+    }
+}
+
+impl RuntimeState {
+    pub fn reset(&mut self) {
+        *self = RuntimeState::default();
+    }
+
+    /*
+        Tightens by one level if the projected runtime is below the time
+        left, loosens by one if it is above it by more than the slack.
+        Changes are held for some runs, the new power draw needs time to
+        show in the average
+    */
+    pub fn update(
+        &mut self,
+        energy: f64,
+        power: f64,
+        needed: u64,
+        levels: usize,
+        slack: u8,
+    ) -> Option<Projection> {
+        self.held += 1;
+        // no discharge rate, e.g. right after unplugging, keeps the average
+        if power <= 0.0 {
+            return None;
+        }
+        let power = match self.power {
+            Some(average) => average + (power - average) * SMOOTHING,
+            None => power,
+        };
+        self.power = Some(power);
+        let projected = (energy / power * 3600.0) as u64;
+        if self.held >= HOLD_RUNS {
+            if projected < needed && self.level < levels {
+                self.level += 1;
+                self.held = 0;
+            } else if projected > needed + needed * slack as u64 / 100 && self.level > 0 {
+                self.level -= 1;
+                self.held = 0;
+            }
+        }
+        self.level = self.level.min(levels);
+        Some(Projection {
+            needed,
+            projected,
+            power,
+        })
+    }
+}
+
+fn default_slack() -> Option<u8> {
+    Some(20)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 36Wh last 3h at 12W
+    const ENERGY: f64 = 36.0;
+    const HOURS: u64 = 3600;
+
+    fn run(state: &mut RuntimeState, power: f64, needed: u64, runs: u32) -> Option<Projection> {
+        (0..runs)
+            .map(|_| state.update(ENERGY, power, needed, 3, 20))
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn smooths_the_power() {
+        let mut state = RuntimeState::default();
+        let projection = state.update(ENERGY, 12.0, 2 * HOURS, 3, 20).unwrap();
+        assert_eq!(projection.projected, 3 * HOURS);
+        assert_eq!(projection.power, 12.0);
+        let projection = state.update(ENERGY, 22.0, 2 * HOURS, 3, 20).unwrap();
+        assert_eq!(projection.power, 12.0 + 10.0 * SMOOTHING);
+    }
+
+    #[test]
+    fn holds_each_level() {
+        let mut state = RuntimeState::default();
+        run(&mut state, 12.0, 4 * HOURS, HOLD_RUNS - 1);
+        assert_eq!(state.level, 0);
+        run(&mut state, 12.0, 4 * HOURS, 1);
+        assert_eq!(state.level, 1);
+        run(&mut state, 12.0, 4 * HOURS, HOLD_RUNS - 1);
+        assert_eq!(state.level, 1);
+        run(&mut state, 12.0, 4 * HOURS, 1);
+        assert_eq!(state.level, 2);
+        // never beyond the last level
+        run(&mut state, 12.0, 4 * HOURS, 5 * HOLD_RUNS);
+        assert_eq!(state.level, 3);
+    }
+
+    #[test]
+    fn loosens_only_above_the_slack() {
+        let mut state = RuntimeState {
+            level: 2,
+            ..Default::default()
+        };
+        // 3h projected, 2.5h needed plus 20% is exactly 3h
+        run(&mut state, 12.0, 9000, 2 * HOLD_RUNS);
+        assert_eq!(state.level, 2);
+        run(&mut state, 12.0, 2 * HOURS, HOLD_RUNS);
+        assert_eq!(state.level, 1);
+        run(&mut state, 12.0, 2 * HOURS, HOLD_RUNS);
+        assert_eq!(state.level, 0);
+    }
+
+    #[test]
+    fn missing_discharge_rate() {
+        let mut state = RuntimeState::default();
+        assert_eq!(state.update(ENERGY, 0.0, 4 * HOURS, 3, 20), None);
+        assert_eq!(state.power, None);
+        state.update(ENERGY, 12.0, 4 * HOURS, 3, 20);
+        assert_eq!(run(&mut state, 0.0, 4 * HOURS, 2 * HOLD_RUNS), None);
+        assert_eq!(state.power, Some(12.0));
+        assert_eq!(state.level, 0);
+        // the held runs count, the next reading may change the level
+        run(&mut state, 12.0, 4 * HOURS, 1);
+        assert_eq!(state.level, 1);
+    }
+}
@@ -0,0 +1,173 @@
+use serde_derive::Deserialize;
+
+/*
+    Time-of-day and weekday schedules that switch the base profile
+*/
+
+pub const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Schedule {
+    pub profile: String,
+    pub start: String,
+    pub end: String,
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub ac_power: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalTime {
+    // 0 = sunday, as in WEEKDAYS
+    pub weekday: usize,
+    pub minutes: u32,
+}
+
+/*
+    "HH:MM" to minutes since midnight
+*/
+pub fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let hours = hours.parse::<u32>().ok()?;
+    let minutes = minutes.parse::<u32>().ok()?;
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+pub fn get_local_time() -> LocalTime {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    LocalTime {
+        weekday: tm.tm_wday as usize % 7,
+        minutes: (tm.tm_hour * 60 + tm.tm_min) as u32,
+    }
+}
+
+impl Schedule {
+    fn runs_on(&self, weekday: usize) -> bool {
+        self.days.is_empty()
+            || self
+                .days
+                .iter()
+                .any(|day| day.eq_ignore_ascii_case(WEEKDAYS[weekday % 7]))
+    }
+
+    /*
+        Windows ending before they start run over midnight and belong to the
+        day they start on, equal start and end cover the whole day
+    */
+    pub fn is_active(&self, time: &LocalTime, ac_power: bool) -> bool {
+        if self.ac_power.is_some_and(|ac| ac != ac_power) {
+            return false;
+        }
+        let (start, end) = match (parse_time(&self.start), parse_time(&self.end)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return false,
+        };
+        if start < end {
+            self.runs_on(time.weekday) && time.minutes >= start && time.minutes < end
+        } else if start > end {
+            (self.runs_on(time.weekday) && time.minutes >= start)
+                || (self.runs_on(time.weekday + 6) && time.minutes < end)
+        } else {
+            self.runs_on(time.weekday)
+        }
+    }
+}
+
+/*
+    Overlapping schedules: the first one in the config file wins
+*/
+pub fn get_active_schedule<'a>(
+    schedules: &'a [Schedule],
+    time: &LocalTime,
+    ac_power: bool,
+) -> Option<&'a Schedule> {
+    schedules
+        .iter()
+        .find(|schedule| schedule.is_active(time, ac_power))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(profile: &str, start: &str, end: &str, days: &[&str]) -> Schedule {
+        Schedule {
+            profile: profile.to_string(),
+            start: start.to_string(),
+            end: end.to_string(),
+            days: days.iter().map(|day| day.to_string()).collect(),
+            ac_power: None,
+        }
+    }
+
+    fn at(weekday: usize, time: &str) -> LocalTime {
+        LocalTime {
+            weekday,
+            minutes: parse_time(time).unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("00:00"), Some(0));
+        assert_eq!(parse_time("23:59"), Some(1439));
+        assert_eq!(parse_time("7:05"), Some(425));
+        assert_eq!(parse_time("24:00"), None);
+        assert_eq!(parse_time("12:60"), None);
+        assert_eq!(parse_time("1200"), None);
+    }
+
+    #[test]
+    fn window_over_midnight_belongs_to_its_start_day() {
+        // friday 22:00 until saturday 06:00
+        let night = schedule("quiet", "22:00", "06:00", &["fri"]);
+        assert!(!night.is_active(&at(5, "21:59"), true));
+        assert!(night.is_active(&at(5, "22:00"), true));
+        assert!(night.is_active(&at(6, "00:00"), true));
+        assert!(night.is_active(&at(6, "05:59"), true));
+        assert!(!night.is_active(&at(6, "06:00"), true));
+        assert!(!night.is_active(&at(6, "22:00"), true));
+        assert!(!night.is_active(&at(5, "05:00"), true));
+        // saturday night runs into sunday, the first weekday
+        let weekend = schedule("quiet", "22:00", "06:00", &["sat"]);
+        assert!(weekend.is_active(&at(0, "01:00"), true));
+        assert!(!weekend.is_active(&at(1, "01:00"), true));
+    }
+
+    #[test]
+    fn matches_weekdays_and_power_source() {
+        let work = schedule("work", "09:00", "17:00", &["Mon", "tue"]);
+        assert!(work.is_active(&at(1, "09:00"), true));
+        assert!(work.is_active(&at(2, "16:59"), false));
+        assert!(!work.is_active(&at(2, "17:00"), true));
+        assert!(!work.is_active(&at(3, "12:00"), true));
+        let every_day = schedule("all", "08:00", "08:00", &[]);
+        assert!((0..7).all(|day| every_day.is_active(&at(day, "03:00"), true)));
+        let plugged_in = Schedule {
+            ac_power: Some(true),
+            ..work
+        };
+        assert!(plugged_in.is_active(&at(1, "12:00"), true));
+        assert!(!plugged_in.is_active(&at(1, "12:00"), false));
+    }
+
+    #[test]
+    fn first_schedule_wins() {
+        let schedules = vec![
+            schedule("evening", "18:00", "23:00", &[]),
+            schedule("night", "20:00", "07:00", &[]),
+        ];
+        let active =
+            |time| get_active_schedule(&schedules, &at(3, time), true).map(|s| s.profile.as_str());
+        assert_eq!(active("19:00"), Some("evening"));
+        assert_eq!(active("21:00"), Some("evening"));
+        assert_eq!(active("23:30"), Some("night"));
+        assert_eq!(active("06:59"), Some("night"));
+        assert_eq!(active("12:00"), None);
+    }
+}
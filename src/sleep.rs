@@ -0,0 +1,289 @@
+use crossterm::style::Stylize;
+use nix::fcntl::OFlag;
+use nix::unistd::pipe2;
+use serde_derive::Deserialize;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedFd;
+
+/*
+    Suspend/resume awareness via logind's PrepareForSleep signal
+*/
+
+// below the default InhibitDelayMaxSec of logind
+const SUSPEND_TIMEOUT: Duration = Duration::from_secs(4);
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SuspendConfig {
+    pub governor: Option<String>,
+    pub turbo: Option<bool>,
+}
+
+/*
+    Suspend carries the sender to confirm that the suspend settings are
+    applied, dropping it confirms as well
+*/
+#[derive(Debug)]
+pub enum SleepEvent {
+    Suspend(Sender<()>),
+    Resume,
+}
+
+pub struct SleepWatcher {
+    events: Receiver<SleepEvent>,
+    wakeup: RawFd,
+}
+
+fn get_logind_proxy(connection: &Connection) -> zbus::Result<Proxy<'static>> {
+    Proxy::new(
+        connection,
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+    )
+}
+
+/*
+    A delay lock holds the suspend back until it is closed, at most
+    InhibitDelayMaxSec (5 seconds by default)
+*/
+fn take_delay_lock(proxy: &Proxy) -> Option<OwnedFd> {
+    match proxy.call(
+        "Inhibit",
+        &("sleep", "yablo", "Applying suspend settings", "delay"),
+    ) {
+        Ok(fd) => Some(fd),
+        Err(x) => {
+            eprintln!(
+                "[{}] Warning: Can't take sleep delay lock: {}",
+                "!".yellow(),
+                x
+            );
+            None
+        }
+    }
+}
+
+fn wake(fd: RawFd) {
+    let _ = nix::unistd::write(fd, &[1]);
+}
+
+/*
+    Listens on the given bus in a background thread and passes the events to
+    the main loop, which wakes up on fd(). With a delay lock a suspend waits
+    until the main loop confirmed that the suspend settings are applied.
+*/
+pub fn watch_sleep(connection: &Connection, delay_lock: bool) -> zbus::Result<SleepWatcher> {
+    let proxy = get_logind_proxy(connection)?;
+    let signals = proxy.receive_signal("PrepareForSleep")?;
+    let (wakeup, wakeup_write) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+        .map_err(|x| zbus::Error::from(std::io::Error::from(x)))?;
+    let (sender, events) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut lock = match delay_lock {
+            true => take_delay_lock(&proxy),
+            false => None,
+        };
+        for message in signals {
+            let start: bool = match message.body().deserialize() {
+                Ok(start) => start,
+                Err(_) => continue,
+            };
+            if start {
+                let (done, applied) = mpsc::channel();
+                if sender.send(SleepEvent::Suspend(done)).is_err() {
+                    break;
+                }
+                wake(wakeup_write);
+                // a stuck main loop mustn't hold the suspend back
+                let _ = applied.recv_timeout(SUSPEND_TIMEOUT);
+                lock = None;
+            } else {
+                if sender.send(SleepEvent::Resume).is_err() {
+                    break;
+                }
+                wake(wakeup_write);
+                if delay_lock && lock.is_none() {
+                    lock = take_delay_lock(&proxy);
+                }
+            }
+        }
+        let _ = nix::unistd::close(wakeup_write);
+    });
+    Ok(SleepWatcher { events, wakeup })
+}
+
+impl SleepWatcher {
+    /*
+        Readable while events are waiting
+    */
+    pub fn fd(&self) -> RawFd {
+        self.wakeup
+    }
+
+    pub fn try_recv(&self) -> Option<SleepEvent> {
+        let mut buf = [0u8; 64];
+        while let Ok(len) = nix::unistd::read(self.wakeup, &mut buf) {
+            if len == 0 {
+                break;
+            }
+        }
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for SleepWatcher {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.wakeup);
+    }
+}
+
+/*
+    Against a private dbus-daemon with a fake logind, skipped without
+    dbus-daemon
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testbus::Bus;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::sync::{Arc, Mutex};
+
+    const LOGIND_PATH: &str = "/org/freedesktop/login1";
+
+    /*
+        Hands out the write end of a pipe as delay lock, the read end sees EOF
+        once yablo closed the lock
+    */
+    struct FakeLogind {
+        locks: Arc<Mutex<Vec<File>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeLogind {
+        fn inhibit(
+            &self,
+            what: &str,
+            _who: &str,
+            _why: &str,
+            mode: &str,
+        ) -> zbus::fdo::Result<OwnedFd> {
+            if what != "sleep" || mode != "delay" {
+                return Err(zbus::fdo::Error::InvalidArgs(format!("{} {}", what, mode)));
+            }
+            let (read, write) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)
+                .map_err(|x| zbus::fdo::Error::IOError(x.to_string()))?;
+            self.locks
+                .lock()
+                .unwrap()
+                .push(unsafe { File::from_raw_fd(read) });
+            Ok(OwnedFd::from(unsafe {
+                std::os::fd::OwnedFd::from_raw_fd(write)
+            }))
+        }
+    }
+
+    fn start_logind(bus: &Bus) -> (Connection, Arc<Mutex<Vec<File>>>) {
+        let locks = Arc::new(Mutex::new(Vec::new()));
+        let logind = bus
+            .connect()
+            .name("org.freedesktop.login1")
+            .unwrap()
+            .serve_at(
+                LOGIND_PATH,
+                FakeLogind {
+                    locks: locks.clone(),
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        (logind, locks)
+    }
+
+    fn prepare_for_sleep(logind: &Connection, start: bool) {
+        logind
+            .emit_signal(
+                None::<zbus::names::BusName>,
+                LOGIND_PATH,
+                "org.freedesktop.login1.Manager",
+                "PrepareForSleep",
+                &(start,),
+            )
+            .unwrap();
+    }
+
+    fn wait_for_event(watcher: &SleepWatcher) -> Option<SleepEvent> {
+        let mut fds = [PollFd::new(watcher.fd(), PollFlags::POLLIN)];
+        poll(&mut fds, 5000).ok()?;
+        watcher.try_recv()
+    }
+
+    fn is_open(lock: &File) -> bool {
+        let mut buf = [0u8; 1];
+        nix::unistd::read(lock.as_raw_fd(), &mut buf).is_err()
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) -> bool {
+        for _ in 0..50 {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn holds_suspend_until_applied() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => return eprintln!("dbus-daemon not found, skipped"),
+        };
+        let (logind, locks) = start_logind(&bus);
+        let watcher = watch_sleep(&bus.connect().build().unwrap(), true).unwrap();
+        assert!(wait_until(|| locks.lock().unwrap().len() == 1));
+
+        prepare_for_sleep(&logind, true);
+        let done = match wait_for_event(&watcher) {
+            Some(SleepEvent::Suspend(done)) => done,
+            event => panic!("expected suspend, got {:?}", event),
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(is_open(&locks.lock().unwrap()[0]));
+        done.send(()).unwrap();
+        assert!(wait_until(|| !is_open(&locks.lock().unwrap()[0])));
+
+        prepare_for_sleep(&logind, false);
+        assert!(matches!(wait_for_event(&watcher), Some(SleepEvent::Resume)));
+        // a new lock for the next suspend
+        assert!(wait_until(|| {
+            let locks = locks.lock().unwrap();
+            locks.len() == 2 && is_open(&locks[1])
+        }));
+    }
+
+    #[test]
+    fn reports_sleep_without_lock() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => return eprintln!("dbus-daemon not found, skipped"),
+        };
+        let (logind, locks) = start_logind(&bus);
+        let watcher = watch_sleep(&bus.connect().build().unwrap(), false).unwrap();
+
+        prepare_for_sleep(&logind, true);
+        assert!(matches!(
+            wait_for_event(&watcher),
+            Some(SleepEvent::Suspend(_))
+        ));
+        prepare_for_sleep(&logind, false);
+        assert!(matches!(wait_for_event(&watcher), Some(SleepEvent::Resume)));
+        assert!(watcher.try_recv().is_none());
+        assert!(locks.lock().unwrap().is_empty());
+    }
+}
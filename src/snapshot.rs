@@ -0,0 +1,145 @@
+use crate::cgroup;
+use crate::wifi;
+use crossterm::style::Stylize;
+
+/*
+    Startup snapshot of every sysfs/procfs value yablo may change,
+    written back in reverse order when the program exits
+*/
+
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    entries: Vec<(String, String)>,
+    // Wi-Fi power save is set over nl80211 instead of a file
+    wifi_power_save: Vec<(String, bool)>,
+    // CPUQuota= and CPUWeight= are set through systemd
+    unit_properties: Vec<(String, &'static str, u64)>,
+}
+
+impl Snapshot {
+    pub fn save(&mut self, path: &str) {
+        if self.entries.iter().any(|(p, _)| p == path) {
+            return;
+        }
+        match std::fs::read_to_string(path) {
+            Ok(value) => self
+                .entries
+                .push((path.to_string(), value.trim_end().to_string())),
+            Err(x) => eprintln!("[{}] Warning: Can't save '{}': {}", "!".yellow(), path, x),
+        }
+    }
+
+    /*
+        For files whose content differs from what has to be written back,
+        e.g. the bracketed choice lists of module parameters
+    */
+    pub fn save_value(&mut self, path: &str, value: &str) {
+        if self.entries.iter().any(|(p, _)| p == path) {
+            return;
+        }
+        self.entries.push((path.to_string(), value.to_string()));
+    }
+
+    pub fn save_wifi_power_save(&mut self, interface: &str, enabled: bool) {
+        if self.original_wifi_power_save(interface).is_some() {
+            return;
+        }
+        self.wifi_power_save.push((interface.to_string(), enabled));
+    }
+
+    pub fn original_wifi_power_save(&self, interface: &str) -> Option<bool> {
+        self.wifi_power_save
+            .iter()
+            .find(|(i, _)| i == interface)
+            .map(|(_, enabled)| *enabled)
+    }
+
+    pub fn save_unit_property(&mut self, unit: &str, property: &'static str, value: u64) {
+        if self.original_unit_property(unit, property).is_some() {
+            return;
+        }
+        self.unit_properties
+            .push((unit.to_string(), property, value));
+    }
+
+    pub fn original_unit_property(&self, unit: &str, property: &str) -> Option<u64> {
+        self.unit_properties
+            .iter()
+            .find(|(u, p, _)| u == unit && *p == property)
+            .map(|(_, _, value)| *value)
+    }
+
+    pub fn original(&self, path: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(p, _)| p == path)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /*
+        Writes the value, or the original one if no value is given, but only if
+        it differs from the current content
+    */
+    pub fn write_or_restore(&self, path: &str, value: Option<&str>) -> std::io::Result<()> {
+        let output = match value.or_else(|| self.original(path)) {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        if std::fs::read_to_string(path)?.trim_end() == output {
+            return Ok(());
+        }
+        std::fs::write(path, output)
+    }
+
+    /*
+        Some values depend on each other (e.g. min_perf_pct <= max_perf_pct), so
+        writes that fail are retried once after all others were restored
+    */
+    pub fn restore(&self) {
+        let failed: Vec<&(String, String)> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|(path, value)| std::fs::write(path, value).is_err())
+            .collect();
+        for (path, value) in failed {
+            match std::fs::write(path, value) {
+                Ok(_) => (),
+                Err(x) => eprintln!(
+                    "[{}] Warning: Can't restore '{}': {}",
+                    "!".yellow(),
+                    path,
+                    x
+                ),
+            }
+        }
+        for (interface, enabled) in self.wifi_power_save.iter() {
+            match wifi::set_power_save(interface, *enabled) {
+                Ok(_) => (),
+                Err(x) => eprintln!(
+                    "[{}] Warning: Can't restore power save of '{}': {}",
+                    "!".yellow(),
+                    interface,
+                    x
+                ),
+            }
+        }
+        // units systemd has unloaded in the meantime don't need it
+        for (unit, property, value) in self.unit_properties.iter() {
+            match cgroup::get_unit_property(unit, property) {
+                Ok(current) if current != *value => {
+                    if let Err(x) = cgroup::set_unit_property(unit, property, *value) {
+                        eprintln!(
+                            "[{}] Warning: Can't restore {} of '{}': {}",
+                            "!".yellow(),
+                            property,
+                            unit,
+                            x
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+/*
+    Reading and writing the single value files of sysfs and procfs. Missing
+    or unreadable files are None, most of them depend on hardware and driver
+*/
+
+pub fn read_trimmed(path: &str) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(value) => Some(value.trim().to_string()),
+        Err(_) => None,
+    }
+}
+
+/*
+    e.g. a frequency or a counter
+*/
+pub fn read_value<T: std::str::FromStr>(path: &str) -> Option<T> {
+    read_trimmed(path)?.parse::<T>().ok()
+}
+
+/*
+    Sorted names of the entries, empty if the directory can't be read
+*/
+pub fn list_dir(path: &str) -> Vec<String> {
+    let mut entries: Vec<String> = match std::fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort();
+    entries
+}
+
+/*
+    Like list_dir with the directory in front of each name
+*/
+pub fn list_paths(path: &str) -> Vec<String> {
+    list_dir(path)
+        .into_iter()
+        .map(|entry| format!("{}/{}", path, entry))
+        .collect()
+}
+
+/*
+    Write errors of std::fs don't name the file
+*/
+pub fn write(path: &str, value: &str) -> std::io::Result<()> {
+    std::fs::write(path, value)
+        .map_err(|x| std::io::Error::new(x.kind(), format!("couldn't write '{}': {}", path, x)))
+}
@@ -0,0 +1,180 @@
+use crate::driver::SYSFS_PATH;
+use crate::sysfs;
+
+/*
+    Core type detection for hybrid CPUs (Intel P-cores/E-cores, ARM big.LITTLE)
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoreType {
+    Performance,
+    Efficiency,
+    Uniform,
+}
+
+impl CoreType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoreType::Performance => "Performance cores",
+            CoreType::Efficiency => "Efficiency cores",
+            CoreType::Uniform => "CPUs",
+        }
+    }
+}
+
+// below the sysfs root
+const CPU_PATH: &str = "devices/system/cpu/cpu";
+
+fn read_cpu_list(path: &str) -> Option<Vec<i32>> {
+    match std::fs::read_to_string(path) {
+        Ok(list) => Some(crate::parse_cpu_list(&list)),
+        Err(_) => None,
+    }
+}
+
+fn read_cpu_value(sysfs: &str, cpu: i32, file: &str) -> Option<i64> {
+    sysfs::read_value(&format!("{}/{}{}/{}", sysfs, CPU_PATH, cpu, file))
+}
+
+/*
+    Ranks CPUs by a per-CPU value: the CPUs with the highest value are performance
+    cores, all others efficiency cores. Returns None if the value is missing or
+    identical on all CPUs
+*/
+fn rank_cpus(cpus: &[i32], value: impl Fn(i32) -> Option<i64>) -> Option<Vec<(i32, CoreType)>> {
+    let values: Vec<i64> = cpus.iter().map(|cpu| value(*cpu)).collect::<Option<_>>()?;
+    let max = *values.iter().max()?;
+    if values.iter().all(|v| *v == max) {
+        return None;
+    }
+    Some(
+        cpus.iter()
+            .zip(values.iter())
+            .map(|(cpu, v)| {
+                if *v == max {
+                    (*cpu, CoreType::Performance)
+                } else {
+                    (*cpu, CoreType::Efficiency)
+                }
+            })
+            .collect(),
+    )
+}
+
+/*
+    Detection order: the Intel hybrid PMU cpu lists, the scheduler's
+    cpu_capacity (ARM) and finally the maximum frequency of clustered CPUs
+*/
+pub fn get_core_types(cpus: &[i32]) -> Vec<(i32, CoreType)> {
+    get_core_types_at(SYSFS_PATH, cpus)
+}
+
+fn get_core_types_at(sysfs: &str, cpus: &[i32]) -> Vec<(i32, CoreType)> {
+    if let (Some(performance), Some(efficiency)) = (
+        read_cpu_list(&format!("{}/devices/cpu_core/cpus", sysfs)),
+        read_cpu_list(&format!("{}/devices/cpu_atom/cpus", sysfs)),
+    ) {
+        return cpus
+            .iter()
+            .map(|cpu| {
+                if efficiency.contains(cpu) && !performance.contains(cpu) {
+                    (*cpu, CoreType::Efficiency)
+                } else {
+                    (*cpu, CoreType::Performance)
+                }
+            })
+            .collect();
+    }
+    if let Some(types) = rank_cpus(cpus, |cpu| read_cpu_value(sysfs, cpu, "cpu_capacity")) {
+        return types;
+    }
+    // x86 reports a cluster per L2 and boosts favored cores higher, so cluster
+    // frequencies only tell core types apart on ARM
+    let clustered = cfg!(not(any(target_arch = "x86", target_arch = "x86_64")))
+        && cpus
+            .iter()
+            .all(|cpu| read_cpu_value(sysfs, *cpu, "topology/cluster_id").is_some());
+    if clustered {
+        if let Some(types) = rank_cpus(cpus, |cpu| {
+            read_cpu_value(sysfs, cpu, "cpufreq/cpuinfo_max_freq")
+        }) {
+            return types;
+        }
+    }
+    cpus.iter().map(|cpu| (*cpu, CoreType::Uniform)).collect()
+}
+
+pub fn is_hybrid(core_types: &[(i32, CoreType)]) -> bool {
+    core_types
+        .iter()
+        .any(|(_, core_type)| *core_type != CoreType::Uniform)
+}
+
+pub fn get_cpus_of_type(core_types: &[(i32, CoreType)], wanted: CoreType) -> Vec<i32> {
+    core_types
+        .iter()
+        .filter(|(_, core_type)| *core_type == wanted)
+        .map(|(cpu, _)| *cpu)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(sysfs: &std::path::Path, file: &str, content: &str) {
+        let path = sysfs.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn core_types(sysfs: &tempfile::TempDir, cpus: &[i32]) -> Vec<(i32, CoreType)> {
+        get_core_types_at(sysfs.path().to_str().unwrap(), cpus)
+    }
+
+    #[test]
+    fn intel_hybrid_pmu() {
+        let sysfs = tempfile::tempdir().unwrap();
+        create(sysfs.path(), "devices/cpu_core/cpus", "0-3\n");
+        create(sysfs.path(), "devices/cpu_atom/cpus", "4-7\n");
+        let types = core_types(&sysfs, &[0, 2, 4, 7]);
+        assert_eq!(get_cpus_of_type(&types, CoreType::Performance), vec![0, 2]);
+        assert_eq!(get_cpus_of_type(&types, CoreType::Efficiency), vec![4, 7]);
+        assert!(is_hybrid(&types));
+    }
+
+    #[test]
+    fn cpu_capacity() {
+        let sysfs = tempfile::tempdir().unwrap();
+        for cpu in 0..6 {
+            let capacity = if cpu < 4 { "446\n" } else { "1024\n" };
+            let file = format!("devices/system/cpu/cpu{}/cpu_capacity", cpu);
+            create(sysfs.path(), &file, capacity);
+        }
+        let types = core_types(&sysfs, &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(get_cpus_of_type(&types, CoreType::Performance), vec![4, 5]);
+        assert_eq!(
+            get_cpus_of_type(&types, CoreType::Efficiency),
+            vec![0, 1, 2, 3]
+        );
+        // a missing value on one CPU can't rank them
+        assert!(!is_hybrid(&core_types(&sysfs, &[0, 4, 6])));
+    }
+
+    #[test]
+    fn uniform_cpus() {
+        let sysfs = tempfile::tempdir().unwrap();
+        for cpu in 0..4 {
+            let file = format!("devices/system/cpu/cpu{}/cpu_capacity", cpu);
+            create(sysfs.path(), &file, "1024\n");
+        }
+        // only one of the hybrid PMUs
+        create(sysfs.path(), "devices/cpu_core/cpus", "0-3\n");
+        let types = core_types(&sysfs, &[0, 1, 2, 3]);
+        assert!(!is_hybrid(&types));
+        assert_eq!(
+            get_cpus_of_type(&types, CoreType::Uniform),
+            vec![0, 1, 2, 3]
+        );
+    }
+}
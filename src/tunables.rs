@@ -0,0 +1,178 @@
+use crate::snapshot::Snapshot;
+use crossterm::style::Stylize;
+use serde_derive::Deserialize;
+
+/*
+    Kernel tunables applied on battery and reverted on AC power
+*/
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TunablesConfig {
+    pub audio_power_save: Option<u32>,
+    pub audio_power_save_controller: Option<bool>,
+    pub nmi_watchdog: Option<bool>,
+    pub dirty_writeback_centisecs: Option<u32>,
+    pub laptop_mode: Option<u32>,
+    #[serde(default)]
+    pub sysctl: std::collections::BTreeMap<String, toml::Value>,
+}
+
+/*
+    sysctl keys are either dotted names like "vm.swappiness" or absolute paths
+    below /proc/sys or /sys
+*/
+pub fn get_sysctl_path(key: &str) -> Option<String> {
+    if key.starts_with('/') {
+        if (key.starts_with("/proc/sys/") || key.starts_with("/sys/")) && !key.contains("..") {
+            Some(key.to_string())
+        } else {
+            None
+        }
+    } else if !key.is_empty() && !key.contains('/') {
+        Some(format!("/proc/sys/{}", key.replace('.', "/")))
+    } else {
+        None
+    }
+}
+
+fn format_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(String::from(if *value { "1" } else { "0" })),
+        _ => None,
+    }
+}
+
+pub fn get_tunables(config: &TunablesConfig) -> Result<Vec<(String, String)>, String> {
+    let mut tunables: Vec<(String, String)> = Vec::new();
+    if let Some(value) = config.audio_power_save {
+        tunables.push((
+            String::from("/sys/module/snd_hda_intel/parameters/power_save"),
+            value.to_string(),
+        ));
+    }
+    if let Some(value) = config.audio_power_save_controller {
+        tunables.push((
+            String::from("/sys/module/snd_hda_intel/parameters/power_save_controller"),
+            String::from(if value { "Y" } else { "N" }),
+        ));
+    }
+    if let Some(value) = config.nmi_watchdog {
+        tunables.push((
+            String::from("/proc/sys/kernel/nmi_watchdog"),
+            String::from(if value { "1" } else { "0" }),
+        ));
+    }
+    if let Some(value) = config.dirty_writeback_centisecs {
+        tunables.push((
+            String::from("/proc/sys/vm/dirty_writeback_centisecs"),
+            value.to_string(),
+        ));
+    }
+    if let Some(value) = config.laptop_mode {
+        tunables.push((String::from("/proc/sys/vm/laptop_mode"), value.to_string()));
+    }
+    for (key, value) in config.sysctl.iter() {
+        let path = match get_sysctl_path(key) {
+            Some(path) => path,
+            None => return Err(format!("invalid sysctl key '{}'", key)),
+        };
+        match format_value(value) {
+            Some(value) => tunables.push((path, value)),
+            None => return Err(format!("invalid value for sysctl key '{}'", key)),
+        }
+    }
+    Ok(tunables)
+}
+
+pub fn save_tunables(tunables: &[(String, String)], snapshot: &mut Snapshot) {
+    for (path, _) in tunables.iter() {
+        snapshot.save(path);
+    }
+}
+
+/*
+    Called on power source transitions only, so manual changes on AC or on
+    battery stay untouched until the next transition
+*/
+pub fn set_tunables(tunables: &[(String, String)], on_battery: bool, snapshot: &Snapshot) {
+    for (path, value) in tunables.iter() {
+        let value = if on_battery {
+            Some(value.as_str())
+        } else {
+            None
+        };
+        match snapshot.write_or_restore(path, value) {
+            Ok(_) => (),
+            Err(x) => eprintln!("[{}] Warning: Can't write '{}': {}", "!".yellow(), path, x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sysctl_keys() {
+        assert_eq!(
+            get_sysctl_path("vm.swappiness").as_deref(),
+            Some("/proc/sys/vm/swappiness")
+        );
+        assert_eq!(
+            get_sysctl_path("kernel.nmi_watchdog").as_deref(),
+            Some("/proc/sys/kernel/nmi_watchdog")
+        );
+        assert_eq!(
+            get_sysctl_path("/sys/module/snd_hda_intel/parameters/power_save").as_deref(),
+            Some("/sys/module/snd_hda_intel/parameters/power_save")
+        );
+        assert_eq!(get_sysctl_path("/proc/sys/../../etc/passwd"), None);
+        assert_eq!(get_sysctl_path("/etc/sysctl.conf"), None);
+        assert_eq!(get_sysctl_path("vm/swappiness"), None);
+        assert_eq!(get_sysctl_path(""), None);
+    }
+
+    #[test]
+    fn sysctl_values() {
+        let config: TunablesConfig = toml::from_str(
+            r#"
+            laptop_mode = 5
+            [sysctl]
+            "vm.swappiness" = 10
+            "vm.dirty_ratio" = "20"
+            "kernel.nmi_watchdog" = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_tunables(&config).unwrap(),
+            vec![
+                ("/proc/sys/vm/laptop_mode".to_string(), "5".to_string()),
+                ("/proc/sys/kernel/nmi_watchdog".to_string(), "0".to_string()),
+                ("/proc/sys/vm/dirty_ratio".to_string(), "20".to_string()),
+                ("/proc/sys/vm/swappiness".to_string(), "10".to_string()),
+            ]
+        );
+        let config: TunablesConfig = toml::from_str("[sysctl]\n\"vm.x\" = [1]").unwrap();
+        assert!(get_tunables(&config).is_err());
+        let config: TunablesConfig = toml::from_str("[sysctl]\n\"/dev/null\" = 1").unwrap();
+        assert!(get_tunables(&config).is_err());
+    }
+
+    #[test]
+    fn reverted_on_ac() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("swappiness").to_str().unwrap().to_string();
+        std::fs::write(&path, "60\n").unwrap();
+        let tunables = vec![(path.clone(), "10".to_string())];
+        let mut snapshot = Snapshot::default();
+        save_tunables(&tunables, &mut snapshot);
+        set_tunables(&tunables, true, &snapshot);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "10");
+        set_tunables(&tunables, false, &snapshot);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), "60");
+    }
+}
@@ -0,0 +1,191 @@
+use nix::sys::socket::{bind, recv, MsgFlags, NetlinkAddr, SockAddr};
+use std::io::Error;
+use std::os::unix::io::RawFd;
+
+/*
+    Kernel uevents (NETLINK_KOBJECT_UEVENT) to wake up on power supply
+    changes instead of waiting for the next poll
+*/
+
+// multicast group of the events sent by the kernel, udev rebroadcasts on group 2
+const KERNEL_EVENTS: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub vars: Vec<(String, String)>,
+}
+
+impl Uevent {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/*
+    Kernel events are "action@devpath" followed by KEY=VALUE pairs, all
+    separated by null bytes
+*/
+pub fn parse_uevent(buf: &[u8]) -> Option<Uevent> {
+    let mut fields = buf
+        .split(|byte| *byte == 0)
+        .filter(|field| !field.is_empty())
+        .map(|field| String::from_utf8_lossy(field).to_string());
+    let header = fields.next()?;
+    let (action, devpath) = header.split_once('@')?;
+    let vars = fields
+        .filter_map(|field| {
+            field
+                .split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        })
+        .collect();
+    Some(Uevent {
+        action: action.to_string(),
+        devpath: devpath.to_string(),
+        vars,
+    })
+}
+
+/*
+    Only adapters report POWER_SUPPLY_ONLINE, batteries send change events
+    on every capacity update
+*/
+pub fn is_power_source_event(event: &Uevent) -> bool {
+    event.get("SUBSYSTEM") == Some("power_supply") && event.get("POWER_SUPPLY_ONLINE").is_some()
+}
+
+pub struct UeventSocket {
+    fd: RawFd,
+}
+
+impl UeventSocket {
+    pub fn open() -> std::io::Result<UeventSocket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let socket = UeventSocket { fd };
+        bind(fd, &SockAddr::Netlink(NetlinkAddr::new(0, KERNEL_EVENTS)))?;
+        Ok(socket)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /*
+        Reads all pending events and returns true if a power source changed
+    */
+    pub fn read_events(&self) -> bool {
+        let mut buf = vec![0u8; 8192];
+        let mut power_event = false;
+        while let Ok(len) = recv(self.fd, &mut buf, MsgFlags::MSG_DONTWAIT) {
+            if let Some(event) = parse_uevent(&buf[..len]) {
+                power_event |= is_power_source_event(&event);
+            }
+        }
+        power_event
+    }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AC_EVENT: &[u8] =
+        b"change@/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC\0\
+        ACTION=change\0\
+        DEVPATH=/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC\0\
+        SUBSYSTEM=power_supply\0\
+        POWER_SUPPLY_NAME=AC\0\
+        POWER_SUPPLY_TYPE=Mains\0\
+        POWER_SUPPLY_ONLINE=0\0\
+        SEQNUM=4121\0";
+
+    const BATTERY_EVENT: &[u8] =
+        b"change@/devices/LNXSYSTM:00/LNXSYBUS:00/PNP0C0A:00/power_supply/BAT0\0\
+        ACTION=change\0\
+        DEVPATH=/devices/LNXSYSTM:00/LNXSYBUS:00/PNP0C0A:00/power_supply/BAT0\0\
+        SUBSYSTEM=power_supply\0\
+        POWER_SUPPLY_NAME=BAT0\0\
+        POWER_SUPPLY_TYPE=Battery\0\
+        POWER_SUPPLY_STATUS=Discharging\0\
+        POWER_SUPPLY_CAPACITY=87\0\
+        SEQNUM=4122\0";
+
+    #[test]
+    fn parses_ac_event() {
+        let event = parse_uevent(AC_EVENT).unwrap();
+        assert_eq!(event.action, "change");
+        assert_eq!(
+            event.devpath,
+            "/devices/LNXSYSTM:00/LNXSYBUS:00/ACPI0003:00/power_supply/AC"
+        );
+        assert_eq!(event.get("POWER_SUPPLY_ONLINE"), Some("0"));
+        assert_eq!(event.get("SEQNUM"), Some("4121"));
+        assert_eq!(event.get("POWER_SUPPLY_CAPACITY"), None);
+        assert!(is_power_source_event(&event));
+    }
+
+    #[test]
+    fn ignores_battery_updates() {
+        let event = parse_uevent(BATTERY_EVENT).unwrap();
+        assert_eq!(event.get("SUBSYSTEM"), Some("power_supply"));
+        assert!(!is_power_source_event(&event));
+    }
+
+    #[test]
+    fn ignores_other_subsystems() {
+        let event = parse_uevent(
+            b"change@/devices/virtual/net/wlan0\0ACTION=change\0SUBSYSTEM=net\0INTERFACE=wlan0\0\
+            POWER_SUPPLY_ONLINE=1\0SEQNUM=4123\0",
+        )
+        .unwrap();
+        assert_eq!(event.devpath, "/devices/virtual/net/wlan0");
+        assert!(!is_power_source_event(&event));
+    }
+
+    #[test]
+    fn handles_truncated_events() {
+        // cut before POWER_SUPPLY_ONLINE and inside its key
+        let cut = AC_EVENT.len() - "POWER_SUPPLY_ONLINE=0\0SEQNUM=4121\0".len();
+        for len in [cut, cut + 12].iter() {
+            let event = parse_uevent(&AC_EVENT[..*len]).unwrap();
+            assert_eq!(event.get("POWER_SUPPLY_NAME"), Some("AC"));
+            assert!(!is_power_source_event(&event));
+        }
+        // header only, without the variables
+        let header = parse_uevent(&AC_EVENT[..20]).unwrap();
+        assert_eq!(header.devpath, "/devices/LNXS");
+        assert!(header.vars.is_empty());
+        // before the '@'
+        assert_eq!(parse_uevent(&AC_EVENT[..4]), None);
+        assert_eq!(parse_uevent(b""), None);
+    }
+
+    #[test]
+    fn rejects_udev_messages() {
+        // udev's rebroadcasts start with a binary "libudev" header instead of action@devpath
+        assert_eq!(
+            parse_uevent(b"libudev\0\xfe\xed\xca\xfe\x28\x00\x00\x00"),
+            None
+        );
+    }
+}
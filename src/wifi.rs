@@ -0,0 +1,481 @@
+use crate::sysfs;
+use nix::sys::socket::{recv, sendto, MsgFlags, NetlinkAddr, SockAddr};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+
+/*
+    Wi-Fi power save over nl80211 (generic netlink), the same requests
+    `iw dev <interface> set power_save on|off` sends
+*/
+
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 0x2;
+const NLMSG_DONE: u16 = 0x3;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const NL80211_CMD_GET_INTERFACE: u8 = 5;
+const NL80211_CMD_SET_POWER_SAVE: u8 = 61;
+const NL80211_CMD_GET_POWER_SAVE: u8 = 62;
+const NL80211_ATTR_IFINDEX: u16 = 3;
+const NL80211_ATTR_IFNAME: u16 = 4;
+const NL80211_ATTR_IFTYPE: u16 = 5;
+const NL80211_ATTR_PS_STATE: u16 = 93;
+const NL80211_IFTYPE_STATION: u32 = 2;
+
+const NET_PATH: &str = "/sys/class/net";
+
+/*
+    Encoding and decoding of netlink messages, all integers in host byte order
+*/
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+pub fn encode_attribute(attr_type: u16, payload: &[u8]) -> Vec<u8> {
+    let len = NLA_HDRLEN + payload.len();
+    let mut attr = Vec::with_capacity(align(len));
+    attr.extend_from_slice(&(len as u16).to_ne_bytes());
+    attr.extend_from_slice(&attr_type.to_ne_bytes());
+    attr.extend_from_slice(payload);
+    attr.resize(align(len), 0);
+    attr
+}
+
+pub fn encode_message(msg_type: u16, flags: u16, seq: u32, cmd: u8, attrs: &[Vec<u8>]) -> Vec<u8> {
+    let len = NLMSG_HDRLEN + GENL_HDRLEN + attrs.iter().map(|attr| attr.len()).sum::<usize>();
+    let mut msg = Vec::with_capacity(len);
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&flags.to_ne_bytes());
+    msg.extend_from_slice(&seq.to_ne_bytes());
+    // port id 0, the kernel assigns one on the first send
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    // generic netlink header: command, version 1, reserved
+    msg.extend_from_slice(&[cmd, 1, 0, 0]);
+    for attr in attrs.iter() {
+        msg.extend_from_slice(attr);
+    }
+    msg
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_ne_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/*
+    Splits a receive buffer into (message type, payload after the netlink header)
+*/
+pub fn parse_messages(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while let (Some(len), Some(msg_type)) = (read_u32(buf, offset), read_u16(buf, offset + 4)) {
+        let len = len as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            break;
+        }
+        messages.push((msg_type, &buf[offset + NLMSG_HDRLEN..offset + len]));
+        offset += align(len);
+    }
+    messages
+}
+
+/*
+    Splits the payload of a generic netlink message into (attribute type, value)
+*/
+pub fn parse_attributes(payload: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    let mut offset = GENL_HDRLEN;
+    while let (Some(len), Some(attr_type)) =
+        (read_u16(payload, offset), read_u16(payload, offset + 2))
+    {
+        let len = len as usize;
+        if len < NLA_HDRLEN || offset + len > payload.len() {
+            break;
+        }
+        attrs.push((
+            attr_type & NLA_TYPE_MASK,
+            &payload[offset + NLA_HDRLEN..offset + len],
+        ));
+        offset += align(len);
+    }
+    attrs
+}
+
+fn find_attribute(payload: &[u8], attr_type: u16) -> Option<&[u8]> {
+    parse_attributes(payload)
+        .into_iter()
+        .find(|(found, _)| *found == attr_type)
+        .map(|(_, value)| value)
+}
+
+fn find_u32(payload: &[u8], attr_type: u16) -> Option<u32> {
+    read_u32(find_attribute(payload, attr_type)?, 0)
+}
+
+/*
+    Requests, built without a socket so they can be checked byte by byte
+*/
+
+pub fn build_get_family(seq: u32, name: &str) -> Vec<u8> {
+    let mut family_name = name.as_bytes().to_vec();
+    family_name.push(0);
+    encode_message(
+        GENL_ID_CTRL,
+        NLM_F_REQUEST | NLM_F_ACK,
+        seq,
+        CTRL_CMD_GETFAMILY,
+        &[encode_attribute(CTRL_ATTR_FAMILY_NAME, &family_name)],
+    )
+}
+
+/*
+    Dumps all wireless interfaces, a dump ends with NLMSG_DONE instead of an ACK
+*/
+pub fn build_get_interfaces(family: u16, seq: u32) -> Vec<u8> {
+    encode_message(
+        family,
+        NLM_F_REQUEST | NLM_F_DUMP,
+        seq,
+        NL80211_CMD_GET_INTERFACE,
+        &[],
+    )
+}
+
+pub fn build_get_power_save(family: u16, seq: u32, ifindex: u32) -> Vec<u8> {
+    encode_message(
+        family,
+        NLM_F_REQUEST | NLM_F_ACK,
+        seq,
+        NL80211_CMD_GET_POWER_SAVE,
+        &[encode_attribute(
+            NL80211_ATTR_IFINDEX,
+            &ifindex.to_ne_bytes(),
+        )],
+    )
+}
+
+pub fn build_set_power_save(family: u16, seq: u32, ifindex: u32, enabled: bool) -> Vec<u8> {
+    encode_message(
+        family,
+        NLM_F_REQUEST | NLM_F_ACK,
+        seq,
+        NL80211_CMD_SET_POWER_SAVE,
+        &[
+            encode_attribute(NL80211_ATTR_IFINDEX, &ifindex.to_ne_bytes()),
+            encode_attribute(NL80211_ATTR_PS_STATE, &(enabled as u32).to_ne_bytes()),
+        ],
+    )
+}
+
+/*
+    Replies
+*/
+
+#[derive(Debug, PartialEq)]
+pub enum Reply<'a> {
+    Data(&'a [u8]),
+    Done,
+    // NLMSG_ERROR, 0 is the ACK, otherwise a negative errno
+    Error(i32),
+}
+
+pub fn parse_reply(msg_type: u16, payload: &[u8]) -> Reply<'_> {
+    match msg_type {
+        NLMSG_ERROR => Reply::Error(read_u32(payload, 0).unwrap_or(0) as i32),
+        NLMSG_DONE => Reply::Done,
+        _ => Reply::Data(payload),
+    }
+}
+
+pub fn parse_family_id(payload: &[u8]) -> Option<u16> {
+    read_u16(find_attribute(payload, CTRL_ATTR_FAMILY_ID)?, 0)
+}
+
+/*
+    Name of a managed (station) interface from a GET_INTERFACE reply. P2P
+    devices have no network interface and access points no power save
+*/
+pub fn parse_station(payload: &[u8]) -> Option<String> {
+    if find_u32(payload, NL80211_ATTR_IFTYPE)? != NL80211_IFTYPE_STATION {
+        return None;
+    }
+    let name = find_attribute(payload, NL80211_ATTR_IFNAME)?;
+    let name = name.split(|byte| *byte == 0).next()?;
+    Some(String::from_utf8_lossy(name).to_string())
+}
+
+pub fn parse_power_save(payload: &[u8]) -> Option<bool> {
+    find_u32(payload, NL80211_ATTR_PS_STATE).map(|state| state != 0)
+}
+
+/*
+    Generic netlink socket
+*/
+
+struct GenlSocket {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl GenlSocket {
+    fn open() -> std::io::Result<GenlSocket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(GenlSocket { fd, seq: 0 })
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /*
+        Sends a request and collects the replies until the ACK or the end of
+        the dump
+    */
+    fn request(&mut self, msg: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+        sendto(
+            self.fd,
+            msg,
+            &SockAddr::Netlink(NetlinkAddr::new(0, 0)),
+            MsgFlags::empty(),
+        )?;
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = recv(self.fd, &mut buf, MsgFlags::empty())?;
+            for (msg_type, payload) in parse_messages(&buf[..len]) {
+                match parse_reply(msg_type, payload) {
+                    Reply::Data(payload) => replies.push(payload.to_vec()),
+                    Reply::Done | Reply::Error(0) => return Ok(replies),
+                    Reply::Error(errno) => return Err(Error::from_raw_os_error(-errno)),
+                }
+            }
+        }
+    }
+
+    fn get_family_id(&mut self, name: &str) -> std::io::Result<u16> {
+        let seq = self.next_seq();
+        self.request(&build_get_family(seq, name))?
+            .iter()
+            .find_map(|reply| parse_family_id(reply))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "nl80211 family not found"))
+    }
+}
+
+impl Drop for GenlSocket {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+/*
+    Wireless interfaces and their power save state
+*/
+
+pub fn get_wifi_interfaces() -> std::io::Result<Vec<String>> {
+    let mut socket = GenlSocket::open()?;
+    let family = socket.get_family_id("nl80211")?;
+    let seq = socket.next_seq();
+    let mut interfaces: Vec<String> = socket
+        .request(&build_get_interfaces(family, seq))?
+        .iter()
+        .filter_map(|reply| parse_station(reply))
+        .collect();
+    interfaces.sort();
+    Ok(interfaces)
+}
+
+fn get_ifindex(interface: &str) -> std::io::Result<u32> {
+    sysfs::read_value::<u32>(&format!("{}/{}/ifindex", NET_PATH, interface))
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no ifindex in sysfs"))
+}
+
+pub fn get_power_save(interface: &str) -> std::io::Result<bool> {
+    let ifindex = get_ifindex(interface)?;
+    let mut socket = GenlSocket::open()?;
+    let family = socket.get_family_id("nl80211")?;
+    let seq = socket.next_seq();
+    socket
+        .request(&build_get_power_save(family, seq, ifindex))?
+        .iter()
+        .find_map(|reply| parse_power_save(reply))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no power save state in reply"))
+}
+
+/*
+    nl80211 only allows this with CAP_NET_ADMIN in the initial user namespace,
+    see yablo.service.d/wifi-power-save.conf
+*/
+pub fn set_power_save(interface: &str, enabled: bool) -> std::io::Result<()> {
+    let ifindex = get_ifindex(interface)?;
+    let mut socket = GenlSocket::open()?;
+    let family = socket.get_family_id("nl80211")?;
+    let seq = socket.next_seq();
+    socket.request(&build_set_power_save(family, seq, ifindex, enabled))?;
+    Ok(())
+}
+
+/*
+    Frames laid out like the kernel sends them on x86, nl80211 registered as
+    family 0x1c and the station interface wlp2s0 with ifindex 3
+*/
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    const FAMILY: u16 = 0x1c;
+
+    // reply to the GET_INTERFACE dump: a station, a P2P device and NLMSG_DONE
+    #[rustfmt::skip]
+    const INTERFACES: [u8; 184] = [
+        // nlmsghdr: len 96, family, NLM_F_MULTI, seq 2, port id
+        0x60, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x02, 0x00, 0x02, 0x00, 0x00, 0x00, 0x39, 0x30, 0x00, 0x00,
+        // NEW_INTERFACE, IFINDEX 3, IFNAME "wlp2s0", IFTYPE station, WIPHY 0
+        0x07, 0x01, 0x00, 0x00, 0x08, 0x00, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x04, 0x00,
+        0x77, 0x6c, 0x70, 0x32, 0x73, 0x30, 0x00, 0x00, 0x08, 0x00, 0x05, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x08, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // WDEV, MAC, GENERATION, SSID "home"
+        0x0c, 0x00, 0x99, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x06, 0x00,
+        0x00, 0x21, 0x6a, 0x12, 0x34, 0x56, 0x00, 0x00, 0x08, 0x00, 0x2e, 0x00, 0x05, 0x00, 0x00, 0x00,
+        0x08, 0x00, 0x34, 0x00, 0x68, 0x6f, 0x6d, 0x65,
+        // nlmsghdr: len 68
+        0x44, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x02, 0x00, 0x02, 0x00, 0x00, 0x00, 0x39, 0x30, 0x00, 0x00,
+        // NEW_INTERFACE, IFTYPE P2P device without IFINDEX and IFNAME, WIPHY 0
+        0x07, 0x01, 0x00, 0x00, 0x08, 0x00, 0x05, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x08, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        // WDEV, MAC, GENERATION
+        0x0c, 0x00, 0x99, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x06, 0x00,
+        0x02, 0x21, 0x6a, 0x12, 0x34, 0x56, 0x00, 0x00, 0x08, 0x00, 0x2e, 0x00, 0x05, 0x00, 0x00, 0x00,
+        // NLMSG_DONE
+        0x14, 0x00, 0x00, 0x00, 0x03, 0x00, 0x02, 0x00, 0x02, 0x00, 0x00, 0x00, 0x39, 0x30, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // SET_POWER_SAVE on ifindex 3, NLM_F_REQUEST | NLM_F_ACK, seq 3
+    #[rustfmt::skip]
+    const SET_POWER_SAVE: [u8; 36] = [
+        0x24, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x3d, 0x01, 0x00, 0x00, 0x08, 0x00, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00, 0x5d, 0x00,
+        0x01, 0x00, 0x00, 0x00,
+    ];
+
+    // NLMSG_ERROR with error 0 and the capped request header
+    #[rustfmt::skip]
+    const ACK: [u8; 36] = [
+        0x24, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x39, 0x30, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // the same with -EPERM, as sent without CAP_NET_ADMIN in the initial user namespace
+    #[rustfmt::skip]
+    const EPERM: [u8; 36] = [
+        0x24, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x39, 0x30, 0x00, 0x00,
+        0xff, 0xff, 0xff, 0xff, 0x24, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // reply to GET_POWER_SAVE with PS_STATE disabled
+    #[rustfmt::skip]
+    const POWER_SAVE: [u8; 28] = [
+        0x1c, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x39, 0x30, 0x00, 0x00,
+        0x3e, 0x01, 0x00, 0x00, 0x08, 0x00, 0x5d, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn replies(buf: &[u8]) -> Vec<Reply<'_>> {
+        parse_messages(buf)
+            .into_iter()
+            .map(|(msg_type, payload)| parse_reply(msg_type, payload))
+            .collect()
+    }
+
+    #[test]
+    fn lists_station_interfaces() {
+        let replies = replies(&INTERFACES);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[2], Reply::Done);
+        let stations: Vec<String> = replies
+            .iter()
+            .filter_map(|reply| match reply {
+                Reply::Data(payload) => parse_station(payload),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stations, vec!["wlp2s0".to_string()]);
+    }
+
+    #[test]
+    fn builds_interface_dump() {
+        #[rustfmt::skip]
+        let expected = vec![
+            0x14, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x01, 0x03, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x01, 0x00, 0x00,
+        ];
+        assert_eq!(build_get_interfaces(FAMILY, 2), expected);
+    }
+
+    #[test]
+    fn builds_set_power_save() {
+        assert_eq!(
+            build_set_power_save(FAMILY, 3, 3, true),
+            SET_POWER_SAVE.to_vec()
+        );
+        let disabled = build_set_power_save(FAMILY, 3, 3, false);
+        assert_eq!(
+            parse_power_save(parse_messages(&disabled)[0].1),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn parses_ack_and_error() {
+        assert_eq!(replies(&ACK), vec![Reply::Error(0)]);
+        assert_eq!(replies(&EPERM), vec![Reply::Error(-libc::EPERM)]);
+    }
+
+    #[test]
+    fn parses_power_save_state() {
+        let replies = replies(&POWER_SAVE);
+        assert_eq!(replies.len(), 1);
+        match replies[0] {
+            Reply::Data(payload) => assert_eq!(parse_power_save(payload), Some(false)),
+            _ => panic!("expected data"),
+        }
+        assert_eq!(parse_station(parse_messages(&POWER_SAVE)[0].1), None);
+    }
+
+    #[test]
+    fn ignores_truncated_frames() {
+        assert!(parse_messages(&INTERFACES[..40]).is_empty());
+        // the attribute list of the first message is cut inside IFNAME
+        let (_, payload) = parse_messages(&INTERFACES)[0];
+        assert_eq!(parse_station(&payload[..20]), None);
+    }
+}